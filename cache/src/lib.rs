@@ -0,0 +1,963 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::{fmt, mem, thread};
+
+/// Most entries the sweeper expires per lock, so lookups wait for one batch
+/// at most.
+const SWEEP_BATCH: usize = 256;
+/// Longest the sweeper sleeps between two checks.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_SHARD_ENTRIES: usize = 64;
+/// Where deadlines too far ahead for `Instant` are put instead, about a
+/// century out. Lets `Duration::MAX` mean "never expire".
+const FAR_FUTURE: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// Which entry makes room when the cache is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum EvictionPolicy {
+    /// The entry read or written longest ago.
+    #[default]
+    Lru,
+    /// The entry read the fewest times, the least recent one among equals.
+    Lfu,
+}
+
+/// Size limits of a cache. `max_bytes` is checked against the weigher's
+/// estimate, see `Cache::with_weigher`.
+///
+/// Keys are spread over `shards` independently locked maps, each holding an
+/// equal share of the limits, so eviction order is only exact within a shard.
+/// Caches too small to give every shard `MIN_SHARD_ENTRIES` use fewer shards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheLimits {
+    pub max_entries: usize,
+    pub max_bytes: Option<usize>,
+    pub policy: EvictionPolicy,
+    pub shards: usize,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        CacheLimits {
+            max_entries: 10000,
+            max_bytes: None,
+            policy: EvictionPolicy::Lru,
+            shards: 16,
+        }
+    }
+}
+
+/// Counters of a cache since it was made, as returned by `Cache::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads that found a fresh value.
+    pub hits: u64,
+    /// Reads that found nothing, or only an expired value.
+    pub misses: u64,
+    pub insertions: u64,
+    /// Entries removed once past their stale window.
+    pub expirations: u64,
+    /// Live entries removed to stay within the limits.
+    pub evictions: u64,
+    pub stale_serves: u64,
+    pub prefetches: u64,
+}
+
+impl CacheStats {
+    /// Share of reads that were hits, 0 before the first read.
+    pub fn hit_rate(&self) -> f64 {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            0.0
+        } else {
+            self.hits as f64 / reads as f64
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Hits: {}, Misses: {}, Hit Rate: {:.1}%, Insertions: {}, Expirations: {}, Evictions: {}, Stale Serves: {}, Prefetches: {}",
+            self.hits,
+            self.misses,
+            self.hit_rate() * 100.0,
+            self.insertions,
+            self.expirations,
+            self.evictions,
+            self.stale_serves,
+            self.prefetches
+        )
+    }
+}
+
+/// Where a cache reads the time. Only differences between two readings
+/// matter, so it must never go backwards.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// `Instant::now`, unaffected by changes to the system time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, so expiry can be tested without
+/// sleeping.
+#[derive(Debug)]
+pub struct ManualClock {
+    origin: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            origin: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.origin + *self.elapsed.lock().unwrap()
+    }
+}
+
+type Weigher<K, V> = Arc<dyn Fn(&K, &V) -> usize + Send + Sync>;
+type ExpiryCallback<K, V> = Arc<dyn Fn(&K, &V) + Send + Sync>;
+
+/// Eviction order: use count (LFU only) then last use, lowest first.
+type Rank = (u64, u64);
+/// Removal order: end of the stale window, then insertion sequence to keep
+/// slots unique.
+type Expiry = (Instant, u64);
+
+struct Entry<V> {
+    value: V,
+    /// Fresh until this time, then only served stale until `expiry`.
+    expire_time: Instant,
+    ttl: Duration,
+    expiry: Expiry,
+    size: usize,
+    hits: u64,
+    rank: Rank,
+    prefetching: bool,
+}
+
+struct Store<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    order: BTreeMap<Rank, K>,
+    /// Every entry by deadline, so expiring costs only the expired entries.
+    expiries: BTreeMap<Expiry, K>,
+    bytes: usize,
+    tick: u64,
+    evictions: u64,
+}
+
+impl<K, V> Default for Store<K, V> {
+    fn default() -> Self {
+        Store {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            expiries: BTreeMap::new(),
+            bytes: 0,
+            tick: 0,
+            evictions: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> Store<K, V> {
+    fn next_rank(&mut self, policy: EvictionPolicy, hits: u64) -> Rank {
+        self.tick += 1;
+        match policy {
+            EvictionPolicy::Lru => (0, self.tick),
+            EvictionPolicy::Lfu => (hits, self.tick),
+        }
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Entry<V>> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.rank);
+        self.expiries.remove(&entry.expiry);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    /// Removes up to `limit` entries whose deadline has passed, earliest
+    /// first, and hands them back for the expiry callback.
+    fn remove_expired(&mut self, now: Instant, limit: usize) -> Vec<(K, V)> {
+        let mut removed = Vec::new();
+        while removed.len() < limit {
+            match self.expiries.first_key_value() {
+                Some(((deadline, _), _)) if *deadline <= now => {}
+                _ => break,
+            }
+            let (_, key) = self.expiries.pop_first().unwrap();
+            if let Some(entry) = self.remove(&key) {
+                removed.push((key, entry.value));
+            }
+        }
+        removed
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.expiries
+            .first_key_value()
+            .map(|((deadline, _), _)| *deadline)
+    }
+
+    fn evict_one(&mut self) -> bool {
+        let Some((_, key)) = self.order.pop_first() else {
+            return false;
+        };
+        if self.remove(&key).is_some() {
+            self.evictions += 1;
+        }
+        true
+    }
+}
+
+/// The part of a cache its sweeper thread works on.
+struct Shared<K, V> {
+    shards: Vec<Mutex<Store<K, V>>>,
+    on_expire: RwLock<Option<ExpiryCallback<K, V>>>,
+    clock: Arc<dyn Clock>,
+    expirations: AtomicU64,
+    /// Set once to stop the sweeper, which waits on `wakeup` between sweeps.
+    stopped: Mutex<bool>,
+    wakeup: Condvar,
+}
+
+impl<K: Hash + Eq + Clone, V> Shared<K, V> {
+    /// Removes up to `batch` due entries from every shard, locking one shard
+    /// at a time. Returns how many went, whether a shard had more due than
+    /// that, and the next deadline left.
+    fn sweep(&self, batch: usize) -> (usize, bool, Option<Instant>) {
+        let now = self.clock.now();
+        let mut removed = 0;
+        let mut more_due = false;
+        let mut next_deadline: Option<Instant> = None;
+        for shard in &self.shards {
+            let expired = {
+                let mut shard_lock = shard.lock().unwrap();
+                let expired = shard_lock.remove_expired(now, batch);
+                if let Some(deadline) = shard_lock.next_deadline() {
+                    next_deadline = Some(next_deadline.map_or(deadline, |next| next.min(deadline)));
+                }
+                expired
+            };
+            removed += expired.len();
+            more_due |= expired.len() == batch;
+            self.notify_expired(expired);
+        }
+        (removed, more_due, next_deadline)
+    }
+
+    /// Counts expired entries and runs the expiry callback, outside of any
+    /// shard lock.
+    fn notify_expired(&self, expired: Vec<(K, V)>) {
+        if expired.is_empty() {
+            return;
+        }
+        self.expirations
+            .fetch_add(expired.len() as u64, Ordering::Relaxed);
+        if let Some(on_expire) = self.on_expire.read().unwrap().as_ref() {
+            for (key, value) in &expired {
+                on_expire(key, value);
+            }
+        }
+    }
+}
+
+/// A sharded, size-bounded map whose entries expire after a per-entry TTL.
+///
+/// Expired entries can optionally be kept for a while and read back with
+/// `get_stale`, and popular entries close to expiry can be picked for a
+/// refresh with `claim_prefetch`. A background thread removes expired
+/// entries until `shutdown`, which dropping the cache also does.
+pub struct Cache<K, V> {
+    shared: Arc<Shared<K, V>>,
+    hasher: RandomState,
+    default_ttl: Duration,
+    limits: CacheLimits,
+    /// Limits applied to each shard.
+    shard_entries: usize,
+    shard_bytes: Option<usize>,
+    weigher: Weigher<K, V>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    insertions: AtomicU64,
+    stale_window: Duration,
+    stale_serves: AtomicU64,
+    prefetch_fraction: f64,
+    prefetch_min_hits: u64,
+    prefetches: AtomicU64,
+    sweeper: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    pub fn new(default_ttl: Duration, limits: CacheLimits) -> Self {
+        Cache::with_clock(default_ttl, limits, Arc::new(MonotonicClock))
+    }
+
+    /// Like `new`, reading the time from `clock`. The sweeper still waits in
+    /// real time, at most `SWEEP_INTERVAL`; call `sweep` to expire entries
+    /// right away.
+    pub fn with_clock(default_ttl: Duration, limits: CacheLimits, clock: Arc<dyn Clock>) -> Self {
+        let shard_num = limits
+            .shards
+            .clamp(1, (limits.max_entries / MIN_SHARD_ENTRIES).max(1));
+        let shared = Arc::new(Shared {
+            shards: (0..shard_num).map(|_| Mutex::default()).collect(),
+            on_expire: RwLock::new(None),
+            clock,
+            expirations: AtomicU64::new(0),
+            stopped: Mutex::new(false),
+            wakeup: Condvar::new(),
+        });
+        let shared_clone = Arc::clone(&shared);
+
+        let sweeper = thread::spawn(move || loop {
+            let (_, more_due, next_deadline) = shared_clone.sweep(SWEEP_BATCH);
+            let wait = if more_due {
+                // More may be due; let lookups in first
+                thread::yield_now();
+                Duration::ZERO
+            } else {
+                let now = shared_clone.clock.now();
+                next_deadline
+                    .map_or(SWEEP_INTERVAL, |deadline| {
+                        deadline.saturating_duration_since(now)
+                    })
+                    .min(SWEEP_INTERVAL)
+            };
+            let stopped = shared_clone.stopped.lock().unwrap();
+            let (stopped, _) = shared_clone
+                .wakeup
+                .wait_timeout_while(stopped, wait, |stopped| !*stopped)
+                .unwrap();
+            if *stopped {
+                break;
+            }
+        });
+
+        Cache {
+            shared,
+            hasher: RandomState::new(),
+            default_ttl,
+            limits,
+            shard_entries: limits.max_entries.div_ceil(shard_num),
+            shard_bytes: limits.max_bytes.map(|max_bytes| max_bytes / shard_num),
+            weigher: Arc::new(|_, _| mem::size_of::<K>() + mem::size_of::<V>()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            insertions: AtomicU64::new(0),
+            stale_window: Duration::ZERO,
+            stale_serves: AtomicU64::new(0),
+            prefetch_fraction: 0.0,
+            prefetch_min_hits: 0,
+            prefetches: AtomicU64::new(0),
+            sweeper: Mutex::new(Some(sweeper)),
+        }
+    }
+
+    /// Estimates the bytes an entry holds, for `CacheLimits::max_bytes`. The
+    /// default only counts the key and value themselves, not their heap data.
+    pub fn with_weigher(
+        mut self,
+        weigher: impl Fn(&K, &V) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.weigher = Arc::new(weigher);
+        self
+    }
+
+    /// Keeps entries for `stale_window` past their expiry so that `get_stale`
+    /// can still return them.
+    pub fn with_stale_window(mut self, stale_window: Duration) -> Self {
+        self.stale_window = stale_window;
+        self
+    }
+
+    /// Lets `claim_prefetch` pick entries read at least `min_hits` times once
+    /// less than `fraction` of their TTL is left. A zero fraction disables it.
+    pub fn with_prefetch(mut self, fraction: f64, min_hits: u64) -> Self {
+        self.prefetch_fraction = fraction.clamp(0.0, 1.0);
+        self.prefetch_min_hits = min_hits;
+        self
+    }
+
+    /// Called with every entry removed because it expired (after its stale
+    /// window, if any), but not for evicted, replaced or removed entries.
+    pub fn on_expire(self, on_expire: impl Fn(&K, &V) + Send + Sync + 'static) -> Self {
+        *self.shared.on_expire.write().unwrap() = Some(Arc::new(on_expire));
+        self
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<Store<K, V>> {
+        let index = self.hasher.hash_one(key) as usize % self.shared.shards.len();
+        &self.shared.shards[index]
+    }
+
+    /// Caches `value` for the default TTL.
+    pub fn put(&self, key: K, value: V) {
+        self.put_with_ttl(key, value, self.default_ttl);
+    }
+
+    /// Caches `value` for `ttl`. A zero TTL removes any previous value and
+    /// caches nothing.
+    pub fn put_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        if ttl.is_zero() || self.shard_entries == 0 {
+            self.remove(&key);
+            return;
+        }
+        let ttl = ttl.min(FAR_FUTURE);
+        let now = self.shared.clock.now();
+        let size = (self.weigher)(&key, &value);
+        if self.shard_bytes.is_some_and(|max_bytes| size > max_bytes) {
+            self.remove(&key);
+            return;
+        }
+
+        let mut expired = Vec::new();
+        {
+            let mut shard_lock = self.shard(&key).lock().unwrap();
+            // A refreshed entry keeps its popularity
+            let hits = shard_lock.remove(&key).map_or(0, |entry| entry.hits);
+            if shard_lock.entries.len() >= self.shard_entries {
+                // Expired entries go first, so live ones are only evicted when needed
+                expired = shard_lock.remove_expired(now, usize::MAX);
+            }
+            while shard_lock.entries.len() >= self.shard_entries
+                || self
+                    .shard_bytes
+                    .is_some_and(|max_bytes| shard_lock.bytes + size > max_bytes)
+            {
+                if !shard_lock.evict_one() {
+                    break;
+                }
+            }
+
+            let rank = shard_lock.next_rank(self.limits.policy, hits);
+            let expire_time = deadline_after(now, ttl);
+            let expiry = (
+                deadline_after(expire_time, self.stale_window),
+                shard_lock.tick,
+            );
+            shard_lock.order.insert(rank, key.clone());
+            shard_lock.expiries.insert(expiry, key.clone());
+            shard_lock.bytes += size;
+            shard_lock.entries.insert(
+                key,
+                Entry {
+                    value,
+                    expire_time,
+                    ttl,
+                    expiry,
+                    size,
+                    hits,
+                    rank,
+                    prefetching: false,
+                },
+            );
+        }
+        self.insertions.fetch_add(1, Ordering::Relaxed);
+        self.shared.notify_expired(expired);
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.get_with_ttl(key).map(|(value, _)| value)
+    }
+
+    /// Returns a fresh value together with the time it has left. Counted as a
+    /// hit or a miss.
+    pub fn get_with_ttl(&self, key: &K) -> Option<(V, Duration)> {
+        let mut shard_lock = self.shard(key).lock().unwrap();
+        let store = &mut *shard_lock;
+        let Some(entry) = store.entries.get(key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let now = self.shared.clock.now();
+        let Some(remaining) = time_left(entry.expire_time, now) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            // Past the stale window as well, no reason to keep it
+            if entry.expiry.0 <= now {
+                let expired = store.remove(key).map(|entry| (key.clone(), entry.value));
+                drop(shard_lock);
+                self.shared.notify_expired(expired.into_iter().collect());
+            }
+            return None;
+        };
+
+        let hits = entry.hits + 1;
+        let old_rank = entry.rank;
+        let rank = store.next_rank(self.limits.policy, hits);
+        store.order.remove(&old_rank);
+        store.order.insert(rank, key.clone());
+        let entry = store.entries.get_mut(key)?;
+        entry.hits = hits;
+        entry.rank = rank;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some((entry.value.clone(), remaining))
+    }
+
+    /// Whether `get_stale` would return a value, without counting a stale
+    /// serve.
+    pub fn has_stale(&self, key: &K) -> bool {
+        let shard_lock = self.shard(key).lock().unwrap();
+        shard_lock
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.expiry.0 > self.shared.clock.now())
+    }
+
+    /// Returns a value that expired less than the stale window ago, or a
+    /// fresh one. Counted as a stale serve either way.
+    pub fn get_stale(&self, key: &K) -> Option<V> {
+        let shard_lock = self.shard(key).lock().unwrap();
+        let entry = shard_lock.entries.get(key)?;
+        if entry.expiry.0 <= self.shared.clock.now() {
+            return None;
+        }
+        self.stale_serves.fetch_add(1, Ordering::Relaxed);
+        Some(entry.value.clone())
+    }
+
+    /// Returns the fresh value, or caches and returns the one `init` makes.
+    /// `init` runs without any lock held, so concurrent callers may each run
+    /// it; the last one to finish is kept.
+    pub fn get_or_insert_with(&self, key: K, init: impl FnOnce() -> V) -> V {
+        if let Some(value) = self.get(&key) {
+            return value;
+        }
+        let value = init();
+        self.put(key, value.clone());
+        value
+    }
+
+    /// True at most once per cached value, when it is popular and close
+    /// enough to expiry that the caller should refresh it in the background.
+    /// The refreshed value replaces the entry through `put` as usual.
+    pub fn claim_prefetch(&self, key: &K) -> bool {
+        if self.prefetch_fraction <= 0.0 {
+            return false;
+        }
+        let mut shard_lock = self.shard(key).lock().unwrap();
+        let Some(entry) = shard_lock.entries.get_mut(key) else {
+            return false;
+        };
+        if entry.prefetching || entry.hits < self.prefetch_min_hits {
+            return false;
+        }
+        let Some(remaining) = time_left(entry.expire_time, self.shared.clock.now()) else {
+            return false;
+        };
+        if remaining > entry.ttl.mul_f64(self.prefetch_fraction) {
+            return false;
+        }
+        entry.prefetching = true;
+        self.prefetches.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Gives up a claim from `claim_prefetch` without a refreshed value, so a
+    /// later read can claim the entry again.
+    pub fn release_prefetch(&self, key: &K) {
+        let mut shard_lock = self.shard(key).lock().unwrap();
+        if let Some(entry) = shard_lock.entries.get_mut(key) {
+            entry.prefetching = false;
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut shard_lock = self.shard(key).lock().unwrap();
+        shard_lock.remove(key).map(|entry| entry.value)
+    }
+
+    /// Removes every entry past its stale window now instead of waiting for
+    /// the sweeper. Returns how many went.
+    pub fn sweep(&self) -> usize {
+        let (removed, _, _) = self.shared.sweep(usize::MAX);
+        removed
+    }
+
+    /// Removes every entry, stale ones included, for which `remove` is true.
+    /// Returns how many went. Like `remove`, this does not count as expiry.
+    pub fn remove_if(&self, remove: impl Fn(&K, &V) -> bool) -> usize {
+        let mut removed = 0;
+        for shard in &self.shared.shards {
+            let mut shard_lock = shard.lock().unwrap();
+            let keys: Vec<K> = shard_lock
+                .entries
+                .iter()
+                .filter(|(key, entry)| remove(key, &entry.value))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &keys {
+                shard_lock.remove(key);
+            }
+            removed += keys.len();
+        }
+        removed
+    }
+
+    /// Empties the cache and returns how many entries it held. Counters are
+    /// kept.
+    pub fn clear(&self) -> usize {
+        let mut removed = 0;
+        for shard in &self.shared.shards {
+            let mut shard_lock = shard.lock().unwrap();
+            removed += shard_lock.entries.len();
+            let evictions = shard_lock.evictions;
+            *shard_lock = Store {
+                evictions,
+                ..Store::default()
+            };
+        }
+        removed
+    }
+
+    /// Every fresh entry with the time it has left, as of the call. Shards are
+    /// copied one at a time, so concurrent writes may or may not show up.
+    pub fn iter(&self) -> std::vec::IntoIter<(K, V, Duration)> {
+        let now = self.shared.clock.now();
+        let mut entries = Vec::new();
+        for shard in &self.shared.shards {
+            let shard_lock = shard.lock().unwrap();
+            entries.extend(shard_lock.entries.iter().filter_map(|(key, entry)| {
+                let remaining = time_left(entry.expire_time, now)?;
+                Some((key.clone(), entry.value.clone(), remaining))
+            }));
+        }
+        entries.into_iter()
+    }
+
+    pub fn get_default_ttl(&self) -> Duration {
+        self.default_ttl
+    }
+
+    pub fn get_limits(&self) -> CacheLimits {
+        self.limits
+    }
+
+    pub fn get_shard_num(&self) -> usize {
+        self.shared.shards.len()
+    }
+
+    /// Entries held, stale ones included.
+    pub fn len(&self) -> usize {
+        self.sum_shards(|shard| shard.entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get_byte_num(&self) -> usize {
+        self.sum_shards(|shard| shard.bytes)
+    }
+
+    pub fn get_eviction_num(&self) -> u64 {
+        self.sum_shards(|shard| shard.evictions)
+    }
+
+    pub fn get_stale_window(&self) -> Duration {
+        self.stale_window
+    }
+
+    pub fn get_stale_serve_num(&self) -> u64 {
+        self.stale_serves.load(Ordering::Relaxed)
+    }
+
+    pub fn get_prefetch_num(&self) -> u64 {
+        self.prefetches.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            insertions: self.insertions.load(Ordering::Relaxed),
+            expirations: self.shared.expirations.load(Ordering::Relaxed),
+            evictions: self.get_eviction_num(),
+            stale_serves: self.get_stale_serve_num(),
+            prefetches: self.get_prefetch_num(),
+        }
+    }
+
+    fn sum_shards<T: std::iter::Sum>(&self, count: impl Fn(&Store<K, V>) -> T) -> T {
+        self.shared
+            .shards
+            .iter()
+            .map(|shard| count(&shard.lock().unwrap()))
+            .sum()
+    }
+}
+
+/// `now + after`, saturated to `FAR_FUTURE` when out of range.
+fn deadline_after(now: Instant, after: Duration) -> Instant {
+    now.checked_add(after).unwrap_or_else(|| now + FAR_FUTURE)
+}
+
+/// Time until `deadline`, `None` once it is reached.
+fn time_left(deadline: Instant, now: Instant) -> Option<Duration> {
+    deadline
+        .checked_duration_since(now)
+        .filter(|left| !left.is_zero())
+}
+
+impl<K, V> Cache<K, V> {
+    /// Whether the sweeper thread is still running.
+    pub fn is_running(&self) -> bool {
+        !*self.shared.stopped.lock().unwrap()
+    }
+
+    /// Stops the sweeper thread and waits for it to finish, interrupting its
+    /// wait. The cache keeps working afterwards, but expired entries are then
+    /// only removed by `sweep` and by reads and writes that meet them.
+    pub fn shutdown(&self) {
+        *self.shared.stopped.lock().unwrap() = true;
+        self.shared.wakeup.notify_all();
+        if let Some(sweeper) = self.sweeper.lock().unwrap().take() {
+            // A panicking expiry callback has already been reported
+            let _ = sweeper.join();
+        }
+    }
+}
+
+impl<K, V> Drop for Cache<K, V> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl<K, V> fmt::Display for Cache<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.is_running() { "Yes" } else { "No" };
+        let max_bytes = match self.limits.max_bytes {
+            Some(max_bytes) => format!("{}B", max_bytes),
+            None => "Unlimited".to_string(),
+        };
+        write!(
+            f,
+            "[Cache] \"Running\": {}, \"Eviction\": {:?}, \"Shards\": {}, \"Stale Window\": {}s, Record Num: {}/{}, Bytes: {}/{}, {}.",
+            status,
+            self.limits.policy,
+            self.get_shard_num(),
+            self.stale_window.as_secs(),
+            self.len(),
+            self.limits.max_entries,
+            self.get_byte_num(),
+            max_bytes,
+            self.stats()
+        )
+    }
+}
+
+impl<K, V> fmt::Debug for Cache<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let clock = Arc::new(ManualClock::new());
+        let limits = CacheLimits {
+            max_entries: 2,
+            ..Default::default()
+        };
+        let cache: Cache<&str, u32> =
+            Cache::with_clock(Duration::from_secs(60), limits, clock.clone())
+                .with_stale_window(Duration::from_secs(10));
+        assert_eq!(cache.stats(), CacheStats::default());
+        assert_eq!(cache.stats().hit_rate(), 0.0);
+
+        cache.put("a", 1);
+        cache.put("b", 2);
+        cache.put("c", 3);
+        assert_eq!(cache.get(&"b"), Some(2));
+        assert_eq!(cache.get(&"c"), Some(3));
+        assert_eq!(cache.get(&"a"), None);
+        cache.put_with_ttl("d", 4, Duration::ZERO);
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.insertions, stats.evictions, stats.hits, stats.misses),
+            (3, 1, 2, 1)
+        );
+
+        // Expired entries are misses, stale reads are counted apart
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get_stale(&"b"), Some(2));
+        assert_eq!(cache.stats().expirations, 0);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.sweep(), 2);
+        let stats = cache.stats();
+        assert_eq!(
+            (
+                stats.hits,
+                stats.misses,
+                stats.stale_serves,
+                stats.expirations
+            ),
+            (2, 2, 1, 2)
+        );
+        assert_eq!(stats.hit_rate(), 0.5);
+        assert!(format!("{}", cache).contains(
+            "Hits: 2, Misses: 2, Hit Rate: 50.0%, Insertions: 3, Expirations: 2, Evictions: 1"
+        ));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let cache: Cache<u32, u32> = Cache::new(Duration::from_secs(60), CacheLimits::default());
+        cache.put(1, 1);
+        // The sweeper thread removes expired entries nobody reads, within
+        // one `SWEEP_INTERVAL` of real time
+        cache.put_with_ttl(2, 2, Duration::from_millis(20));
+        let start = Instant::now();
+        while cache.len() > 1 && start.elapsed() < SWEEP_INTERVAL * 2 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(cache.len(), 1);
+        // Let the sweeper settle into its one second wait
+        thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        cache.shutdown();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(!cache.is_running());
+        assert!(format!("{}", cache).contains("\"Running\": No"));
+        // Still usable, and a second shutdown is harmless
+        assert_eq!(cache.get(&1), Some(1));
+        cache.shutdown();
+
+        // Dropping joins the sweeper, so nothing holds the callback afterwards
+        let marker = Arc::new(());
+        let marker_clone = Arc::clone(&marker);
+        let cache: Cache<u32, u32> = Cache::new(Duration::from_secs(60), CacheLimits::default())
+            .on_expire(move |_, _| {
+                let _ = &marker_clone;
+            });
+        drop(cache);
+        assert_eq!(Arc::strong_count(&marker), 1);
+    }
+
+    #[test]
+    fn test_generic() {
+        let clock = Arc::new(ManualClock::new());
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let expired_clone = Arc::clone(&expired);
+        let cache: Cache<String, u32> = Cache::with_clock(
+            Duration::from_secs(60),
+            CacheLimits::default(),
+            clock.clone(),
+        )
+        .on_expire(move |key: &String, value: &u32| {
+            expired_clone.lock().unwrap().push((key.clone(), *value))
+        });
+
+        cache.put("default".to_string(), 1);
+        cache.put_with_ttl("short".to_string(), 2, Duration::from_secs(5));
+        assert_eq!(
+            cache.get_with_ttl(&"default".to_string()),
+            Some((1, Duration::from_secs(60)))
+        );
+        assert_eq!(cache.len(), 2);
+
+        // Made only once while the value is cached
+        assert_eq!(cache.get_or_insert_with("lazy".to_string(), || 3), 3);
+        assert_eq!(
+            cache.get_or_insert_with("lazy".to_string(), || unreachable!()),
+            3
+        );
+
+        clock.advance(Duration::from_secs(2));
+        let mut entries: Vec<_> = cache.iter().collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                ("default".to_string(), 1, Duration::from_secs(58)),
+                ("lazy".to_string(), 3, Duration::from_secs(58)),
+                ("short".to_string(), 2, Duration::from_secs(3)),
+            ]
+        );
+
+        // Removed entries are not reported as expired
+        assert_eq!(cache.remove(&"lazy".to_string()), Some(3));
+        assert_eq!(cache.remove(&"lazy".to_string()), None);
+        // Expired exactly at its deadline, and swept without waiting
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.sweep(), 1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&"short".to_string()), None);
+        assert_eq!(*expired.lock().unwrap(), vec![("short".to_string(), 2)]);
+
+        // A zero TTL drops the previous value
+        cache.put_with_ttl("default".to_string(), 4, Duration::ZERO);
+        assert_eq!(cache.get(&"default".to_string()), None);
+
+        cache.put("a".to_string(), 5);
+        cache.put("b".to_string(), 6);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.iter().count(), 0);
+        assert_eq!(expired.lock().unwrap().len(), 1);
+
+        // Far too long to represent is as good as forever
+        let forever: Cache<u32, u32> =
+            Cache::with_clock(Duration::MAX, CacheLimits::default(), clock.clone())
+                .with_stale_window(Duration::MAX)
+                .with_prefetch(1.0, 0);
+        forever.put(1, 1);
+        forever.put_with_ttl(2, 2, Duration::MAX);
+        clock.advance(Duration::from_secs(10 * 365 * 24 * 60 * 60));
+        assert_eq!(forever.get(&1), Some(1));
+        assert_eq!(forever.get(&2), Some(2));
+        assert!(forever.claim_prefetch(&2));
+    }
+}
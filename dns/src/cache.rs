@@ -1,87 +1,277 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::fmt;
+use std::{fmt, mem};
 
+use ::cache::{Clock, MonotonicClock};
+pub use ::cache::{CacheLimits, CacheStats, EvictionPolicy};
+
+use crate::domain::DomainName;
+use crate::message::{RecordType, ResourceRecord};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub name: DomainName,
+    pub rtype: RecordType,
+    pub class: u16,
+}
+
+/// What is remembered for one key. Negative answers (RFC 2308) are kept apart
+/// from record sets so they can never be served as an empty positive answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CachedAnswer {
+    Records(Vec<ResourceRecord>),
+    NoData,
+    NXDomain,
+}
+
+impl CachedAnswer {
+    pub fn is_negative(&self) -> bool {
+        !matches!(self, CachedAnswer::Records(_))
+    }
+
+    fn with_ttl(&self, ttl: u32) -> CachedAnswer {
+        match self {
+            CachedAnswer::Records(records) => CachedAnswer::Records(
+                records
+                    .iter()
+                    .map(|record| ResourceRecord {
+                        ttl,
+                        ..record.clone()
+                    })
+                    .collect(),
+            ),
+            negative => negative.clone(),
+        }
+    }
+}
+
+/// TTL of answers served past their expiry, as recommended by RFC 8767.
+pub const STALE_TTL: u32 = 30;
+
+/// Rough memory held by one entry, for `CacheLimits::max_bytes`.
+fn entry_size(key: &CacheKey, answer: &CachedAnswer) -> usize {
+    let records = match answer {
+        CachedAnswer::Records(records) => records
+            .iter()
+            .map(|record| {
+                mem::size_of::<ResourceRecord>() + record.name.len() + record.data.to_string().len()
+            })
+            .sum(),
+        _ => 0,
+    };
+    mem::size_of::<CacheKey>() + mem::size_of::<CachedAnswer>() + key.name.as_str().len() + records
+}
+
+/// DNS answers on top of the generic `cache::Cache`: TTLs come from the
+/// records and are clamped to a configured range, and served records carry
+/// the time they have left.
 pub struct Cache {
-    cache: Arc<Mutex<HashMap<String, (String, SystemTime)>>>,
-    expiration_time: Duration,
-    running: Arc<AtomicBool>,
+    records: ::cache::Cache<CacheKey, CachedAnswer>,
+    min_ttl: Duration,
+    max_ttl: Duration,
 }
 
 impl Cache {
-    pub fn new(expiration_time_seconds: u64) -> Self {
-        let cache = Arc::new(Mutex::new(HashMap::new()));
-        let running = Arc::new(AtomicBool::new(true));
-        let expiration_time = Duration::new(expiration_time_seconds, 0);
-        let cache_clone = Arc::clone(&cache);
-        let running_clone = Arc::clone(&running);
-
-        thread::spawn(move || {
-            while running_clone.load(Ordering::Relaxed) {
-                thread::sleep(Duration::from_secs(1));
-                let now = SystemTime::now();
-                let mut cache_lock = cache_clone.lock().unwrap();
-                cache_lock.retain(|_, &mut (_, ref expire_time)| {
-                    *expire_time > now
-                });
-            }
-        });
+    pub fn new(min_ttl_seconds: u64, max_ttl_seconds: u64, limits: CacheLimits) -> Self {
+        Cache::with_clock(min_ttl_seconds, max_ttl_seconds, limits, Arc::new(MonotonicClock))
+    }
 
+    /// Like `new`, with expiry measured by `clock`. Snapshots still use the
+    /// system time, the only clock that carries over a restart.
+    pub fn with_clock(min_ttl_seconds: u64, max_ttl_seconds: u64, limits: CacheLimits, clock: Arc<dyn Clock>) -> Self {
+        let min_ttl = Duration::from_secs(min_ttl_seconds);
+        let max_ttl = Duration::from_secs(max_ttl_seconds.max(min_ttl_seconds));
         Cache {
-            cache,
-            expiration_time,
-            running,
+            records: ::cache::Cache::with_clock(max_ttl, limits, clock).with_weigher(entry_size),
+            min_ttl,
+            max_ttl,
+        }
+    }
+
+    /// Lets `get_stale` answer while upstreams are down (RFC 8767). See
+    /// `cache::Cache::with_stale_window`.
+    pub fn with_stale_window(mut self, stale_window: Duration) -> Self {
+        self.records = self.records.with_stale_window(stale_window);
+        self
+    }
+
+    /// See `cache::Cache::with_prefetch`.
+    pub fn with_prefetch(mut self, fraction: f64, min_hits: u64) -> Self {
+        self.records = self.records.with_prefetch(fraction, min_hits);
+        self
+    }
+
+    /// Caches a record set for the smallest TTL among its records, clamped to
+    /// the configured range.
+    pub fn put(&self, key: CacheKey, records: Vec<ResourceRecord>) {
+        let ttl = records.iter().map(|record| record.ttl).min().unwrap_or(0);
+        self.insert(key, CachedAnswer::Records(records), self.clamp_ttl(ttl));
+    }
+
+    /// Caches a NXDOMAIN or NODATA answer for `ttl` seconds, clamped to the
+    /// configured range like positive answers.
+    pub fn put_negative(&self, key: CacheKey, answer: CachedAnswer, ttl: u32) {
+        debug_assert!(answer.is_negative());
+        self.insert(key, answer, self.clamp_ttl(ttl));
+    }
+
+    /// Puts back an entry saved by `export`, for the time it had left. Returns
+    /// false for entries that have expired since.
+    pub fn restore(&self, key: CacheKey, answer: CachedAnswer, expire_time: SystemTime) -> bool {
+        let Ok(remaining) = expire_time.duration_since(SystemTime::now()) else {
+            return false;
+        };
+        if remaining.is_zero() {
+            return false;
         }
+        self.insert(key, answer, remaining.min(self.max_ttl));
+        true
     }
 
-    pub fn put(&self, key: String, value: String) {
-        let expire_time = SystemTime::now() + self.expiration_time;
-        let mut cache_lock = self.cache.lock().unwrap();
-        cache_lock.insert(key, (value, expire_time));
+    /// Every entry that is still fresh, with the time it stops being fresh.
+    pub fn export(&self) -> Vec<(CacheKey, CachedAnswer, SystemTime)> {
+        let now = SystemTime::now();
+        self.records
+            .iter()
+            .map(|(key, answer, remaining)| (key, answer, now + remaining))
+            .collect()
     }
 
-    pub fn get(&self, key: &str, refresh: bool) -> Option<String> {
-        let mut cache_lock = self.cache.lock().unwrap();
-        if let Some((val, expire_time)) = cache_lock.get_mut(key) {
-            if *expire_time > SystemTime::now() {
-                if  refresh {
-                    *expire_time = SystemTime::now() + self.expiration_time;
-                }
-                return Some(val.clone());
-            } else {
-                cache_lock.remove(key);
-            }
+    fn clamp_ttl(&self, ttl: u32) -> Duration {
+        Duration::from_secs(ttl.into()).clamp(self.min_ttl, self.max_ttl)
+    }
+
+    fn insert(&self, key: CacheKey, answer: CachedAnswer, ttl: Duration) {
+        // A zero TTL means the answer must not be reused
+        if ttl.is_zero() {
+            return;
         }
-        None
+        self.records.put_with_ttl(key, answer, ttl);
+    }
+
+    /// Returns the cached answer, with record TTLs set to the time remaining.
+    pub fn get(&self, key: &CacheKey) -> Option<CachedAnswer> {
+        let (answer, remaining) = self.records.get_with_ttl(key)?;
+        let ttl = u32::try_from(remaining.as_secs()).unwrap_or(u32::MAX);
+        Some(answer.with_ttl(ttl))
+    }
+
+    /// Returns an entry that expired less than the stale window ago, with a
+    /// `STALE_TTL` so clients come back soon. Only meant for when every
+    /// upstream has failed.
+    pub fn get_stale(&self, key: &CacheKey) -> Option<CachedAnswer> {
+        let answer = self.records.get_stale(key)?;
+        Some(answer.with_ttl(STALE_TTL))
+    }
+
+    /// See `cache::Cache::has_stale`.
+    pub fn has_stale(&self, key: &CacheKey) -> bool {
+        self.records.has_stale(key)
+    }
+
+    /// See `cache::Cache::claim_prefetch`.
+    pub fn claim_prefetch(&self, key: &CacheKey) -> bool {
+        self.records.claim_prefetch(key)
+    }
+
+    /// See `cache::Cache::sweep`.
+    #[cfg(test)]
+    pub fn sweep(&self) -> usize {
+        self.records.sweep()
+    }
+
+    /// See `cache::Cache::release_prefetch`.
+    pub fn release_prefetch(&self, key: &CacheKey) {
+        self.records.release_prefetch(key)
+    }
+
+    /// Drops every entry. Returns how many went.
+    pub fn flush_all(&self) -> usize {
+        self.records.clear()
+    }
+
+    /// Drops every entry for `name`, whatever its type or class.
+    pub fn flush_name(&self, name: &DomainName) -> usize {
+        self.records.remove_if(|key, _| key.name == *name)
+    }
+
+    /// Drops every entry for `suffix` and the names below it.
+    pub fn flush_suffix(&self, suffix: &DomainName) -> usize {
+        self.records.remove_if(|key, _| key.name.is_subdomain_of(suffix))
+    }
+
+    /// Every fresh entry with the time it has left, sorted by name then
+    /// type. Record TTLs are set to that time, as `get` would serve them.
+    pub fn dump(&self) -> Vec<(CacheKey, CachedAnswer, Duration)> {
+        let mut entries: Vec<_> = self
+            .records
+            .iter()
+            .map(|(key, answer, remaining)| {
+                let ttl = u32::try_from(remaining.as_secs()).unwrap_or(u32::MAX);
+                (key, answer.with_ttl(ttl), remaining)
+            })
+            .collect();
+        entries.sort_by(|(a, _, _), (b, _, _)| {
+            (a.name.as_str(), u16::from(a.rtype), a.class).cmp(&(b.name.as_str(), u16::from(b.rtype), b.class))
+        });
+        entries
     }
-    
 
     pub fn is_running(&self) -> bool {
-        self.running.load(Ordering::Relaxed)
+        self.records.is_running()
+    }
+
+    /// Stops and joins the background sweeper. See `cache::Cache::shutdown`.
+    pub fn shutdown(&self) {
+        self.records.shutdown();
+    }
+
+    pub fn get_min_ttl(&self) -> Duration {
+        self.min_ttl
+    }
+
+    pub fn get_max_ttl(&self) -> Duration {
+        self.max_ttl
     }
 
-    pub fn get_expiration_time(&self) -> Duration {
-        self.expiration_time
+    pub fn get_shard_num(&self) -> usize {
+        self.records.get_shard_num()
     }
 
     pub fn get_record_num(&self) -> usize {
-        self.cache.lock().unwrap().len()
+        self.records.len()
+    }
+
+    /// Fresh negative entries.
+    pub fn get_negative_num(&self) -> usize {
+        self.records
+            .iter()
+            .filter(|(_, answer, _)| answer.is_negative())
+            .count()
+    }
+
+    pub fn get_byte_num(&self) -> usize {
+        self.records.get_byte_num()
+    }
+
+    pub fn get_stale_window(&self) -> Duration {
+        self.records.get_stale_window()
     }
-}
 
-impl Drop for Cache {
-    fn drop(&mut self) {
-        self.running.store(false, Ordering::Relaxed);
+    pub fn stats(&self) -> CacheStats {
+        self.records.stats()
     }
 }
 
 impl fmt::Display for Cache {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let status = if self.is_running() { "Yes" } else { "No" };
-        write!(f, "[Cache] \"Running\": {}, \"Expiration Time\": {}s, Record Num: {}.", status, self.get_expiration_time().as_secs(), self.get_record_num())
+        let limits = self.records.get_limits();
+        let max_bytes = match limits.max_bytes {
+            Some(max_bytes) => format!("{}B", max_bytes),
+            None => "Unlimited".to_string(),
+        };
+        write!(f, "[Cache] \"Running\": {}, \"TTL Range\": {}s-{}s, \"Eviction\": {:?}, \"Shards\": {}, Record Num: {}/{}, Negative Num: {}, Bytes: {}/{}, \"Stale Window\": {}s, {}.", status, self.get_min_ttl().as_secs(), self.get_max_ttl().as_secs(), limits.policy, self.get_shard_num(), self.get_record_num(), limits.max_entries, self.get_negative_num(), self.get_byte_num(), max_bytes, self.get_stale_window().as_secs(), self.stats())
     }
 }
 
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+enum FlightState<V> {
+    Running,
+    Done(V),
+    /// The leading call panicked, waiters do the work themselves.
+    Abandoned,
+}
+
+struct Flight<V> {
+    state: Mutex<FlightState<V>>,
+    done: Condvar,
+}
+
+/// Lets concurrent calls for the same key share one run of the work: the
+/// first call runs it, later ones wait for its result. Nothing is kept once
+/// the work is done, caching the result is up to the caller.
+pub struct Coalescer<K, V> {
+    in_flight: Mutex<HashMap<K, Arc<Flight<V>>>>,
+    coalesced: AtomicU64,
+}
+
+/// Publishes the leading call's outcome, even if its work panics.
+struct Leader<'a, K: Hash + Eq, V> {
+    coalescer: &'a Coalescer<K, V>,
+    key: &'a K,
+    flight: Arc<Flight<V>>,
+}
+
+impl<K: Hash + Eq, V> Drop for Leader<'_, K, V> {
+    fn drop(&mut self) {
+        self.coalescer.in_flight.lock().unwrap().remove(self.key);
+        let mut state = self.flight.state.lock().unwrap();
+        if matches!(*state, FlightState::Running) {
+            *state = FlightState::Abandoned;
+        }
+        drop(state);
+        self.flight.done.notify_all();
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Coalescer<K, V> {
+    pub fn new() -> Self {
+        Coalescer {
+            in_flight: Mutex::new(HashMap::new()),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    /// Runs `work` for `key`, unless a call for the same key is already
+    /// running, in which case this one waits and returns that call's result.
+    pub fn run(&self, key: &K, work: impl FnOnce() -> V) -> V {
+        let (flight, leading) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(key) {
+                Some(flight) => (Arc::clone(flight), false),
+                None => {
+                    let flight = Arc::new(Flight {
+                        state: Mutex::new(FlightState::Running),
+                        done: Condvar::new(),
+                    });
+                    in_flight.insert(key.clone(), Arc::clone(&flight));
+                    (flight, true)
+                }
+            }
+        };
+
+        if leading {
+            let leader = Leader {
+                coalescer: self,
+                key,
+                flight,
+            };
+            let value = work();
+            *leader.flight.state.lock().unwrap() = FlightState::Done(value.clone());
+            return value;
+        }
+
+        let state = flight
+            .done
+            .wait_while(flight.state.lock().unwrap(), |state| {
+                matches!(state, FlightState::Running)
+            })
+            .unwrap();
+        if let FlightState::Done(value) = &*state {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+            return value.clone();
+        }
+        drop(state);
+        work()
+    }
+
+    /// Calls waiting for the running call for `key`, each holding the flight
+    /// besides the map and the leading call.
+    #[cfg(test)]
+    pub fn get_waiter_num(&self, key: &K) -> usize {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, |flight| Arc::strong_count(flight) - 2)
+    }
+
+    /// Calls that got their result from another call.
+    pub fn get_coalesced_num(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
@@ -1,27 +1,37 @@
 use core::fmt;
 use std::{
-    collections::HashMap,
     fs::File,
+    path::PathBuf,
     io::{self, Read, Write},
-    net::UdpSocket,
-    sync::{atomic::AtomicBool, mpsc::TryRecvError, Arc, Mutex},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex,
+    },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+use crossbeam::channel::{self as crossbeam_channel, TrySendError};
 use log::{LogLevel, Logger};
+use scoped_threadpool::Pool;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    cache::Cache,
-    utils::{clean_io, exec_shell_command, query_google_dns},
+    cache::{Cache, CacheKey, CacheLimits, CachedAnswer, EvictionPolicy},
+    coalesce::Coalescer,
+    domain::{DomainError, DomainName},
+    forwarder::{ForwardError, Forwarder, SystemResolver},
+    message::{
+        Message, Rcode, RecordType, ResourceRecord, CLASS_ANY, CLASS_IN, MAX_TCP_PAYLOAD,
+        MAX_UDP_PAYLOAD,
+    },
+    utils::{query_google_dns, GOOGLE_DNS_NOERROR, GOOGLE_DNS_NXDOMAIN},
+    snapshot::{self, SnapshotError},
+    zone::{Zone, ZoneAnswer},
 };
 
-#[derive(Debug, Deserialize)]
-struct DNSRecord {
-    domain: String,
-    ip: String,
-}
-
 #[derive(Debug, Deserialize, Serialize)]
 struct Config {
     dns_config: String,
@@ -29,12 +39,231 @@ struct Config {
     remote_dns_addr: String,
     name: String,
     cache_time: u32,
+    #[serde(default)]
+    legacy_text_protocol: bool,
+    #[serde(default)]
+    refused_domains: Vec<DomainName>,
+    #[serde(default = "default_forward_timeout_ms")]
+    forward_timeout_ms: u64,
+    #[serde(default = "default_client_response_timeout_ms")]
+    client_response_timeout_ms: u64,
+    #[serde(default)]
+    log_unicode_names: bool,
+    #[serde(default)]
+    cache_min_ttl: u64,
+    #[serde(default = "default_cache_max_ttl")]
+    cache_max_ttl: u64,
+    #[serde(default = "default_negative_cache_ttl")]
+    negative_cache_ttl: u32,
+    #[serde(default = "default_cache_max_entries")]
+    cache_max_entries: usize,
+    #[serde(default)]
+    cache_max_bytes: Option<usize>,
+    #[serde(default)]
+    cache_eviction: EvictionPolicy,
+    #[serde(default = "default_cache_shards")]
+    cache_shards: usize,
+    #[serde(default = "default_cache_stale_window")]
+    cache_stale_window: u64,
+    #[serde(default = "default_prefetch_fraction")]
+    prefetch_fraction: f64,
+    #[serde(default = "default_prefetch_min_hits")]
+    prefetch_min_hits: u64,
+    #[serde(default)]
+    cache_snapshot: Option<PathBuf>,
+    #[serde(default = "default_cache_snapshot_interval")]
+    cache_snapshot_interval: u64,
+    #[serde(default = "default_worker_threads")]
+    worker_threads: usize,
+    #[serde(default = "default_request_queue_size")]
+    request_queue_size: usize,
+}
+
+fn default_forward_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_client_response_timeout_ms() -> u64 {
+    1800
+}
+
+fn default_cache_max_ttl() -> u64 {
+    86400
+}
+
+fn default_negative_cache_ttl() -> u32 {
+    300
+}
+
+fn default_cache_max_entries() -> usize {
+    CacheLimits::default().max_entries
+}
+
+fn default_cache_shards() -> usize {
+    CacheLimits::default().shards
+}
+
+fn default_cache_stale_window() -> u64 {
+    86400
+}
+
+fn default_prefetch_fraction() -> f64 {
+    0.1
+}
+
+fn default_prefetch_min_hits() -> u64 {
+    3
+}
+
+fn default_cache_snapshot_interval() -> u64 {
+    300
+}
+
+fn default_worker_threads() -> usize {
+    4
+}
+
+fn default_request_queue_size() -> usize {
+    256
+}
+
+/// A received datagram and the client to answer.
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Longest a blocked thread waits before checking for exit or stop again.
+const EXIT_POLL: Duration = Duration::from_millis(100);
+
+/// Longest a TCP client may stay idle between queries (RFC 7766 section 6.2.3).
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TCP connections served at once, further ones are closed right away.
+const MAX_TCP_CONNECTIONS: usize = 64;
+
+/// Final outcome of running a name through every resolution stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    Answer(Vec<ResourceRecord>),
+    NoData,
+    NXDomain,
+    ServFail,
+    Refused,
+}
+
+impl From<CachedAnswer> for Resolution {
+    fn from(answer: CachedAnswer) -> Self {
+        match answer {
+            CachedAnswer::Records(records) => Resolution::Answer(records),
+            CachedAnswer::NoData => Resolution::NoData,
+            CachedAnswer::NXDomain => Resolution::NXDomain,
+        }
+    }
+}
+
+impl Resolution {
+    pub fn rcode(&self) -> Rcode {
+        match self {
+            Resolution::Answer(_) | Resolution::NoData => Rcode::NoError,
+            Resolution::NXDomain => Rcode::NXDomain,
+            Resolution::ServFail => Rcode::ServFail,
+            Resolution::Refused => Rcode::Refused,
+        }
+    }
+}
+
+#[derive(Default)]
+struct OutcomeCounters {
+    answer: AtomicU64,
+    no_data: AtomicU64,
+    nx_domain: AtomicU64,
+    serv_fail: AtomicU64,
+    refused: AtomicU64,
+}
+
+impl OutcomeCounters {
+    fn record(&self, resolution: &Resolution) {
+        let counter = match resolution {
+            Resolution::Answer(_) => &self.answer,
+            Resolution::NoData => &self.no_data,
+            Resolution::NXDomain => &self.nx_domain,
+            Resolution::ServFail => &self.serv_fail,
+            Resolution::Refused => &self.refused,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for OutcomeCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[Outcomes] \"Answer\": {}, \"NoData\": {}, \"NXDomain\": {}, \"ServFail\": {}, \"Refused\": {}.",
+            self.answer.load(Ordering::Relaxed),
+            self.no_data.load(Ordering::Relaxed),
+            self.nx_domain.load(Ordering::Relaxed),
+            self.serv_fail.load(Ordering::Relaxed),
+            self.refused.load(Ordering::Relaxed)
+        )
+    }
+}
+
+/// The stage of `lookup` a final answer came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Cache,
+    Local,
+    Remote,
+    GoogleDns,
+    System,
+    /// An expired cache entry, served because every upstream failed.
+    Stale,
+}
+
+#[derive(Default)]
+struct StageCounters {
+    cache: AtomicU64,
+    local: AtomicU64,
+    remote: AtomicU64,
+    google_dns: AtomicU64,
+    system: AtomicU64,
+    stale: AtomicU64,
+}
+
+impl StageCounters {
+    fn record(&self, stage: Stage) {
+        let counter = match stage {
+            Stage::Cache => &self.cache,
+            Stage::Local => &self.local,
+            Stage::Remote => &self.remote,
+            Stage::GoogleDns => &self.google_dns,
+            Stage::System => &self.system,
+            Stage::Stale => &self.stale,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for StageCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[Stages] \"Cache\": {}, \"Local\": {}, \"Remote\": {}, \"Google DNS\": {}, \"System\": {}, \"Stale\": {}.",
+            self.cache.load(Ordering::Relaxed),
+            self.local.load(Ordering::Relaxed),
+            self.remote.load(Ordering::Relaxed),
+            self.google_dns.load(Ordering::Relaxed),
+            self.system.load(Ordering::Relaxed),
+            self.stale.load(Ordering::Relaxed)
+        )
+    }
 }
 
 #[derive(Debug)]
 pub enum HandleType {
     ProcessingRequest,
+    ProcessingTcp,
     ProcessingCommand,
+    Prefetch,
+    CacheSnapshot,
 }
 pub struct HandleRecord {
     handle_type: HandleType,
@@ -43,16 +272,45 @@ pub struct HandleRecord {
 }
 
 pub struct DNSServer {
-    dns_config: HashMap<String, String>,
+    dns_config: Zone,
     server_socket: UdpSocket,
+    /// Answers that do not fit in a datagram, on the same port. `None` for
+    /// the legacy text protocol, which is UDP only.
+    tcp_listener: Option<TcpListener>,
     port: u16,
     name: String,
     remote_addr: String,
+    forwarder: Option<Forwarder>,
+    /// Shared by every Google DNS query, so connections are reused.
+    google_client: reqwest::blocking::Client,
+    system_resolver: Option<SystemResolver>,
+    /// How long a client waits for upstreams when a stale answer could be
+    /// served instead (RFC 8767 section 5).
+    client_response_timeout: Duration,
     config_file_path: String,
+    legacy_text_protocol: bool,
+    refused_domains: Vec<DomainName>,
+    log_unicode_names: bool,
+    negative_cache_ttl: u32,
+    outcomes: OutcomeCounters,
+    stages: StageCounters,
     logger: Logger,
-    cache: Mutex<Cache>,
+    cache: Cache,
+    /// One upstream query per (name, type) at a time, shared by every lookup
+    /// that misses the cache meanwhile.
+    upstream_queries: Coalescer<CacheKey, Option<(Stage, Resolution)>>,
+    prefetch_sender: Sender<CacheKey>,
+    prefetch_receiver: Mutex<Receiver<CacheKey>>,
+    cache_snapshot: Option<PathBuf>,
+    cache_snapshot_interval: Duration,
+    worker_threads: usize,
+    request_queue_size: usize,
+    /// Queries answered SERVFAIL right away because the queue was full.
+    shed_queries: AtomicU64,
     stop_request: AtomicBool,
-    exit: AtomicBool,
+    exit: Mutex<bool>,
+    /// Wakes threads waiting in `wait_for_exit`.
+    exit_signal: Condvar,
     handles: Mutex<Vec<HandleRecord>>,
 }
 
@@ -64,15 +322,6 @@ impl DNSServer {
         Ok(contents)
     }
 
-    fn parse_dns_config(contents: &str) -> Result<HashMap<String, String>, serde_json::Error> {
-        let data: Vec<DNSRecord> = serde_json::from_str(contents)?;
-        let dns_config = data
-            .into_iter()
-            .map(|record| (record.domain, record.ip))
-            .collect();
-        Ok(dns_config)
-    }
-
     pub fn new(config_fp: &str, loglevel: LogLevel) -> Self {
         // Read config
         let mut file = File::open(config_fp).expect("Unable to open file");
@@ -92,7 +341,31 @@ impl DNSServer {
 
         // Set Cache
         logger.log(LogLevel::Info, "Setting Cache.");
-        let cache = Cache::new(config.cache_time.into());
+        let limits = CacheLimits {
+            max_entries: config.cache_max_entries,
+            max_bytes: config.cache_max_bytes,
+            policy: config.cache_eviction,
+            shards: config.cache_shards,
+        };
+        let cache = Cache::new(config.cache_min_ttl, config.cache_max_ttl, limits)
+            .with_stale_window(Duration::from_secs(config.cache_stale_window))
+            .with_prefetch(config.prefetch_fraction, config.prefetch_min_hits);
+        if let Some(path) = &config.cache_snapshot {
+            match snapshot::load(&cache, path) {
+                Ok(restored) => logger.log(
+                    LogLevel::Info,
+                    format!("Restored {} Cache Entries from {}", restored, path.display()),
+                ),
+                Err(SnapshotError::Io(e)) if e.kind() == io::ErrorKind::NotFound => logger.log(
+                    LogLevel::Info,
+                    format!("No Cache Snapshot at {}, Starting Cold", path.display()),
+                ),
+                Err(e) => logger.log(
+                    LogLevel::Warning,
+                    format!("Ignoring Cache Snapshot {}: {}", path.display(), e),
+                ),
+            }
+        }
         logger.log(LogLevel::Debug, format!("{}", cache));
         logger.log(LogLevel::Info, "Finish Setting Cache");
 
@@ -100,139 +373,645 @@ impl DNSServer {
         logger.log(LogLevel::Info, "Creating Socket");
         let server_socket =
             UdpSocket::bind(("0.0.0.0", config.dns_port)).expect("Failed to bind socket");
-        let _ = server_socket.set_nonblocking(true);
+        // Blocking, but never for long, so the receive loop still sees exit
+        let _ = server_socket.set_read_timeout(Some(EXIT_POLL));
+        let tcp_listener = if config.legacy_text_protocol {
+            None
+        } else {
+            // The UDP port, so that a configured port of 0 gives both the same one
+            let port = server_socket.local_addr().expect("Failed to bind socket").port();
+            let listener = TcpListener::bind(("0.0.0.0", port)).expect("Failed to bind socket");
+            // Accepting never blocks, so the accept loop still sees exit
+            listener
+                .set_nonblocking(true)
+                .expect("Failed to bind socket");
+            Some(listener)
+        };
         logger.log(LogLevel::Info, "Finish Creating Socket");
 
         // Load DNS Config
         logger.log(LogLevel::Info, "Loading DNS Config");
         let contents =
             Self::load_config_file(&config.dns_config).expect("Failed to open dns config file");
-        let dns_config = Self::parse_dns_config(&contents).expect("Failed to parse DNS config");
+        let dns_config =
+            Zone::parse(&contents, config.cache_time).expect("Failed to parse DNS config");
         logger.log(LogLevel::Debug, format!("[DNS Config] {:#?}", &dns_config));
-        logger.log(LogLevel::Info, "Finish Loading DNS Config");
+        logger.log(
+            LogLevel::Info,
+            format!(
+                "Finish Loading DNS Config, {} Records",
+                dns_config.get_record_num()
+            ),
+        );
+
+        // Set Forwarder
+        let forwarder = Forwarder::parse_addr(&config.remote_dns_addr).map(|upstream| {
+            Forwarder::new(upstream, Duration::from_millis(config.forward_timeout_ms))
+        });
+        match &forwarder {
+            Some(forwarder) => logger.log(
+                LogLevel::Info,
+                format!("Forwarding to {}", forwarder.get_upstream()),
+            ),
+            None => logger.log(
+                LogLevel::Warning,
+                format!(
+                    "Invalid remote_dns_addr {}, Forwarding Disabled",
+                    config.remote_dns_addr
+                ),
+            ),
+        }
+
+        // Set Google DNS Client
+        let forward_timeout = Duration::from_millis(config.forward_timeout_ms);
+        let google_client = reqwest::blocking::Client::builder()
+            .timeout(forward_timeout)
+            .build()
+            .expect("Failed to build HTTP client");
+
+        // Set System Resolver
+        let dns_port = config.dns_port;
+        let system_resolver = Self::load_config_file(SystemResolver::RESOLV_CONF)
+            .ok()
+            .and_then(|contents| SystemResolver::from_resolv_conf(&contents, forward_timeout))
+            // Asking ourselves would only loop
+            .and_then(|resolver| {
+                resolver.without(|addr| addr.ip().is_loopback() && addr.port() == dns_port)
+            });
+        match &system_resolver {
+            Some(resolver) => logger.log(
+                LogLevel::Info,
+                format!("System Nameservers {:?}", resolver.get_nameservers()),
+            ),
+            None => logger.log(
+                LogLevel::Warning,
+                "No Usable Nameserver in resolv.conf, System DNS Disabled",
+            ),
+        }
         logger.log(LogLevel::Info, "--------------------INIT--------------------");
 
+        let (prefetch_sender, prefetch_receiver) = mpsc::channel();
 
         DNSServer {
             dns_config,
             server_socket,
+            tcp_listener,
             port: config.dns_port,
             name: config.name,
             remote_addr: config.remote_dns_addr,
+            forwarder,
+            google_client,
+            system_resolver,
+            client_response_timeout: Duration::from_millis(config.client_response_timeout_ms),
             config_file_path: config_fp.to_string(),
+            legacy_text_protocol: config.legacy_text_protocol,
+            refused_domains: config.refused_domains,
+            log_unicode_names: config.log_unicode_names,
+            negative_cache_ttl: config.negative_cache_ttl,
+            outcomes: OutcomeCounters::default(),
+            stages: StageCounters::default(),
             logger,
-            cache: Mutex::new(cache),
+            cache,
+            upstream_queries: Coalescer::new(),
+            prefetch_sender,
+            prefetch_receiver: Mutex::new(prefetch_receiver),
+            cache_snapshot: config.cache_snapshot,
+            cache_snapshot_interval: Duration::from_secs(config.cache_snapshot_interval),
+            worker_threads: config.worker_threads.max(1),
+            request_queue_size: config.request_queue_size.max(1),
+            shed_queries: AtomicU64::new(0),
             stop_request: AtomicBool::new(false),
-            exit: AtomicBool::new(false),
+            exit: Mutex::new(false),
+            exit_signal: Condvar::new(),
             handles: Mutex::new(Vec::<HandleRecord>::new()),
         }
     }
 
+    /// The port actually bound, which differs from `port` when that is 0.
+    pub fn get_local_port(&self) -> u16 {
+        self.server_socket
+            .local_addr()
+            .map_or(self.port, |addr| addr.port())
+    }
+
     pub fn add_handle_record(&self, handle_record: HandleRecord) {
         self.handles.lock().unwrap().push(handle_record)
     }
 
-    fn resolve_dns(&self, domain: &str) -> String {
-        // Clean domain
-        let cleaned_domain = clean_io(domain);
+    fn resolve_dns(&self, domain: &str, qtype: RecordType) -> Resolution {
+        // Names are validated as sent, `parse` alone would trim surrounding whitespace
+        let parsed = match domain.chars().find(|c| c.is_whitespace()) {
+            Some(c) => Err(DomainError::InvalidCharacter(c)),
+            None => DomainName::parse(domain),
+        };
+        let cleaned_domain = match parsed {
+            Ok(cleaned_domain) => cleaned_domain,
+            Err(e) => {
+                self.logger.log(
+                    LogLevel::Warning,
+                    format!("Invalid domain {:?}: {}", domain, e),
+                );
+                self.outcomes.record(&Resolution::Refused);
+                return Resolution::Refused;
+            }
+        };
+        let resolution = self.lookup(&cleaned_domain, qtype);
+        self.outcomes.record(&resolution);
+        match &resolution {
+            Resolution::Answer(_) => {}
+            Resolution::NoData => self.logger.log(
+                LogLevel::Info,
+                format!("No {} records for {}", qtype, self.shown(&cleaned_domain)),
+            ),
+            Resolution::NXDomain => self.logger.log(
+                LogLevel::Warning,
+                format!("Domain not exist: {}", self.shown(&cleaned_domain)),
+            ),
+            Resolution::ServFail => self.logger.log(
+                LogLevel::Error,
+                format!("All upstreams failed, domain: {}", self.shown(&cleaned_domain)),
+            ),
+            Resolution::Refused => self.logger.log(
+                LogLevel::Warning,
+                format!("Refused by policy, domain: {}", self.shown(&cleaned_domain)),
+            ),
+        }
+        resolution
+    }
+
+    /// How a name appears in logs.
+    fn shown(&self, name: &DomainName) -> String {
+        if self.log_unicode_names {
+            name.to_unicode()
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn is_refused(&self, domain: &DomainName) -> bool {
+        self.refused_domains
+            .iter()
+            .any(|refused| domain.is_subdomain_of(refused))
+    }
+
+    fn lookup(&self, cleaned_domain: &DomainName, qtype: RecordType) -> Resolution {
+        if self.is_refused(cleaned_domain) {
+            return Resolution::Refused;
+        }
 
         // Search in cache
-        if let Some(ip) = self.cache.lock().unwrap().get(&cleaned_domain, false) {
-            self.logger
-                .log(LogLevel::Info, &format!("Cached {}---->{}", domain, ip));
-            return ip.clone();
+        let cache_key = CacheKey {
+            name: cleaned_domain.clone(),
+            rtype: qtype,
+            class: CLASS_IN,
+        };
+        if let Some(answer) = self.cache.get(&cache_key) {
+            self.logger.log(
+                LogLevel::Info,
+                format!(
+                    "Cached {}---->{}",
+                    self.shown(cleaned_domain),
+                    format_cached(&answer)
+                ),
+            );
+            if self.cache.claim_prefetch(&cache_key) {
+                // The receiver only goes away on exit
+                let _ = self.prefetch_sender.send(cache_key);
+            }
+            self.stages.record(Stage::Cache);
+            return answer.into();
         }
 
         // Search in local DNS
-        if let Some(ip) = self.dns_config.get(cleaned_domain.as_str()) {
-            self.logger
-                .log(LogLevel::Info, &format!("Local DNS {}---->{}", domain, ip));
-            self.cache
-                .lock()
-                .unwrap()
-                .put(cleaned_domain.to_string(), ip.to_string());
-            return ip.clone();
+        match self.dns_config.lookup(cleaned_domain, qtype) {
+            ZoneAnswer::Records(records) => {
+                self.logger.log(
+                    LogLevel::Info,
+                    format!(
+                        "Local DNS {}---->{}",
+                        self.shown(cleaned_domain),
+                        format_records(&records)
+                    ),
+                );
+                self.stages.record(Stage::Local);
+                return Resolution::Answer(records);
+            }
+            ZoneAnswer::NoData => {
+                self.stages.record(Stage::Local);
+                return Resolution::NoData;
+            }
+            ZoneAnswer::Alias { chain, target } => {
+                self.logger.log(
+                    LogLevel::Info,
+                    format!(
+                        "Local DNS {}---->{}",
+                        self.shown(cleaned_domain),
+                        format_records(&chain)
+                    ),
+                );
+                // The target is not local, so this ends in the cache or upstream
+                return match self.lookup(&target, qtype) {
+                    Resolution::Answer(records) => {
+                        Resolution::Answer(chain.into_iter().chain(records).collect())
+                    }
+                    // The alias exists even when its target has no such records
+                    Resolution::NoData => Resolution::Answer(chain),
+                    resolution => resolution,
+                };
+            }
+            ZoneAnswer::NotFound => {}
+        }
+
+        // With an expired answer to fall back on, the client is not kept
+        // waiting through every upstream (RFC 8767 section 5)
+        let deadline = self
+            .cache
+            .has_stale(&cache_key)
+            .then(|| Instant::now() + self.client_response_timeout);
+        let upstream = self
+            .upstream_queries
+            .run(&cache_key, || self.query_upstreams(cleaned_domain, qtype, deadline));
+        if let Some((stage, resolution)) = upstream {
+            self.stages.record(stage);
+            return resolution;
+        }
+
+        // Every upstream failed, an expired answer beats none (RFC 8767)
+        if let Some(answer) = self.cache.get_stale(&cache_key) {
+            self.logger.log(
+                LogLevel::Warning,
+                format!(
+                    "Stale {}---->{}",
+                    self.shown(cleaned_domain),
+                    format_cached(&answer)
+                ),
+            );
+            self.stages.record(Stage::Stale);
+            return answer.into();
+        }
+        Resolution::ServFail
+    }
+
+    /// Asks the remote, Google and system resolvers in turn, caching what they
+    /// answer. `None` when all of them failed, or once `deadline` has passed
+    /// before the next one is asked.
+    fn query_upstreams(
+        &self,
+        cleaned_domain: &DomainName,
+        qtype: RecordType,
+        deadline: Option<Instant>,
+    ) -> Option<(Stage, Resolution)> {
+        let out_of_time = || {
+            let expired = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if expired {
+                self.logger.log(
+                    LogLevel::Warning,
+                    format!("Client response timer expired, domain: {}", self.shown(cleaned_domain)),
+                );
+            }
+            expired
+        };
+
+        // Search remote DNS
+        if let Some(forwarder) = &self.forwarder {
+            let response = forwarder.query(cleaned_domain, qtype);
+            if let Some(resolution) =
+                self.upstream_resolution("Remote", cleaned_domain, qtype, response)
+            {
+                return Some((Stage::Remote, resolution));
+            }
         }
 
         // Search Google DNS
-        match query_google_dns(&cleaned_domain, "a") {
-            Ok(dns_response) => {
+        if out_of_time() {
+            return None;
+        }
+        match query_google_dns(&self.google_client, cleaned_domain, &qtype.to_string()) {
+            Ok(dns_response) if dns_response.status == GOOGLE_DNS_NXDOMAIN => {
+                let ttl = dns_response.negative_ttl();
+                self.cache_negative(cleaned_domain, qtype, CachedAnswer::NXDomain, ttl);
+                return Some((Stage::GoogleDns, Resolution::NXDomain));
+            }
+            Ok(dns_response) if dns_response.status == GOOGLE_DNS_NOERROR => {
+                if dns_response.answers.is_empty() {
+                    let ttl = dns_response.negative_ttl();
+                    self.cache_negative(cleaned_domain, qtype, CachedAnswer::NoData, ttl);
+                    return Some((Stage::GoogleDns, Resolution::NoData));
+                }
                 self.logger.log(
                     LogLevel::Warning,
-                    &format!(
+                    format!(
                         "Google DNS {}---->{}",
-                        cleaned_domain.to_string(),
-                        dns_response.ip_address
+                        self.shown(cleaned_domain),
+                        format_records(&dns_response.answers)
+                    ),
+                );
+                self.cache_answer(cleaned_domain, qtype, &dns_response.answers);
+                return Some((Stage::GoogleDns, Resolution::Answer(dns_response.answers)));
+            }
+            Ok(dns_response) => {
+                self.logger.log(
+                    LogLevel::Error,
+                    format!(
+                        "Google DNS returned status {}, domain: {}",
+                        dns_response.status, self.shown(cleaned_domain)
                     ),
                 );
-                self.cache
-                    .lock()
-                    .unwrap()
-                    .put(cleaned_domain.to_string(), dns_response.ip_address.clone());
-                return dns_response.ip_address;
             }
             Err(_) => {
                 self.logger.log(
                     LogLevel::Error,
                     format!(
                         "Error occured when queryiny google dns, domian: {}",
-                        cleaned_domain
+                        self.shown(cleaned_domain)
                     ),
                 );
             }
         }
 
         // Search system DNS
-        let cmd = format!("dig +short {}", cleaned_domain);
-        match exec_shell_command(&cmd) {
-            Ok(ip) => {
-                self.logger.log(
-                    LogLevel::Warning,
-                    &format!("Local DNS not found, system result: {}", ip),
-                );
-                self.cache
-                    .lock()
-                    .unwrap()
-                    .put(cleaned_domain.to_string(), ip.clone());
-                ip
+        if out_of_time() {
+            return None;
+        }
+        if let Some(system_resolver) = &self.system_resolver {
+            let response = system_resolver.query(cleaned_domain, qtype);
+            if let Some(resolution) =
+                self.upstream_resolution("System", cleaned_domain, qtype, response)
+            {
+                return Some((Stage::System, resolution));
             }
+        }
+        None
+    }
+
+    /// Maps an upstream reply to a final resolution, or `None` to move on to
+    /// the next stage.
+    fn upstream_resolution(
+        &self,
+        stage: &str,
+        cleaned_domain: &DomainName,
+        qtype: RecordType,
+        response: Result<Message, ForwardError>,
+    ) -> Option<Resolution> {
+        match response {
+            Ok(response) => match response.header.rcode {
+                Rcode::NXDomain => {
+                    let ttl = response.negative_ttl();
+                    self.cache_negative(cleaned_domain, qtype, CachedAnswer::NXDomain, ttl);
+                    Some(Resolution::NXDomain)
+                }
+                Rcode::NoError if response.answers.is_empty() => {
+                    let ttl = response.negative_ttl();
+                    self.cache_negative(cleaned_domain, qtype, CachedAnswer::NoData, ttl);
+                    Some(Resolution::NoData)
+                }
+                Rcode::NoError => {
+                    self.logger.log(
+                        LogLevel::Warning,
+                        format!(
+                            "{} DNS {}---->{}",
+                            stage,
+                            self.shown(cleaned_domain),
+                            format_records(&response.answers)
+                        ),
+                    );
+                    self.cache_answer(cleaned_domain, qtype, &response.answers);
+                    Some(Resolution::Answer(response.answers))
+                }
+                rcode => {
+                    self.logger.log(
+                        LogLevel::Error,
+                        format!(
+                            "{} DNS returned {:?}, domain: {}",
+                            stage, rcode, self.shown(cleaned_domain)
+                        ),
+                    );
+                    None
+                }
+            },
             Err(e) => {
                 self.logger.log(
                     LogLevel::Error,
-                    &format!("Exception occurred: {}\n Command: {}", e, cmd),
+                    format!("{} DNS failed for {}: {}", stage, self.shown(cleaned_domain), e),
                 );
-                String::new()
+                None
             }
         }
     }
 
-    pub fn processing_request(&self) {
+    /// Upstream answers are cached per (name, type, class) for their own TTL.
+    fn cache_answer(&self, domain: &DomainName, qtype: RecordType, records: &[ResourceRecord]) {
+        let key = CacheKey {
+            name: domain.clone(),
+            rtype: qtype,
+            class: CLASS_IN,
+        };
+        self.cache.put(key, records.to_vec());
+    }
+
+    /// Negative answers are cached for the SOA minimum when the upstream sent
+    /// one, and for `negative_cache_ttl` otherwise.
+    fn cache_negative(
+        &self,
+        domain: &DomainName,
+        qtype: RecordType,
+        answer: CachedAnswer,
+        soa_ttl: Option<u32>,
+    ) {
+        let key = CacheKey {
+            name: domain.clone(),
+            rtype: qtype,
+            class: CLASS_IN,
+        };
+        let ttl = soa_ttl.unwrap_or(self.negative_cache_ttl);
+        self.cache.put_negative(key, answer, ttl);
+    }
+
+    /// Receives one datagram and queues it for the workers, or sheds it when
+    /// they are all busy and the queue is full.
+    pub fn processing_request(&self, queue: &crossbeam_channel::Sender<Datagram>) {
         if self.stop_request.load(std::sync::atomic::Ordering::Relaxed) {
+            // Datagrams wait in the socket buffer until listening resumes
+            self.wait_for_exit(EXIT_POLL);
             return;
         };
         let mut buffer = [0; 1024];
-        match self.server_socket.recv_from(&mut buffer) {
-            Ok((received_bytes, client_address)) => {
-                let requested_domain = String::from_utf8_lossy(&buffer[..received_bytes]);
-                let ip = self.resolve_dns(&requested_domain);
-                self.server_socket
-                    .send_to(ip.as_bytes(), &client_address)
-                    .unwrap();
+        if let Ok((received_bytes, client_address)) = self.server_socket.recv_from(&mut buffer) {
+            let request = buffer[..received_bytes].to_vec();
+            if let Some(reply) = self.enqueue_request(queue, request, client_address) {
+                self.reply(&reply, client_address);
             }
-            Err(_) => return, // Handle timeout or other errors
         }
     }
 
-    pub fn processing_command(self: &Arc<DNSServer>, stdin_channel: &mut std::sync::mpsc::Receiver<String>) {
-        let mut input = String::new();
+    /// Queues a datagram for the workers. Returns the reply to send right
+    /// away instead when the queue is full and the query is shed.
+    pub fn enqueue_request(
+        &self,
+        queue: &crossbeam_channel::Sender<Datagram>,
+        request: Vec<u8>,
+        client_address: SocketAddr,
+    ) -> Option<Vec<u8>> {
+        match queue.try_send((request, client_address)) {
+            Ok(()) => None,
+            Err(TrySendError::Full((request, _))) => self.shed_reply(&request),
+            // Workers only go away once the receive loop has stopped
+            Err(TrySendError::Disconnected(_)) => None,
+        }
+    }
 
-        match stdin_channel.try_recv() {
-            Ok(key) => {
-                input = clean_io(&key);
+    /// Answers a queued datagram, on a worker thread.
+    fn answer_request(&self, request: &[u8], client_address: SocketAddr) {
+        let reply = if self.legacy_text_protocol {
+            let requested_domain = String::from_utf8_lossy(request);
+            // Legacy clients may end the name with a newline
+            let ip = match self.resolve_dns(requested_domain.trim(), RecordType::A) {
+                Resolution::Answer(records) => records
+                    .into_iter()
+                    .find(|record| record.rtype == RecordType::A)
+                    .map(|record| record.data.to_string())
+                    .unwrap_or_default(),
+                _ => String::new(),
+            };
+            Some(ip.into_bytes())
+        } else {
+            self.handle_query(request, MAX_UDP_PAYLOAD)
+        };
+        if let Some(reply) = reply {
+            self.reply(&reply, client_address);
+        }
+    }
+
+    /// Answers SERVFAIL without resolving anything, so clients under overload
+    /// move on to another server instead of waiting for a timeout. Legacy
+    /// clients get the empty reply they get for any failure.
+    fn shed_reply(&self, request: &[u8]) -> Option<Vec<u8>> {
+        let shed = self.shed_queries.fetch_add(1, Ordering::Relaxed) + 1;
+        // One line per thousand, the log must not become the bottleneck
+        if shed % 1000 == 1 {
+            self.logger.log(
+                LogLevel::Warning,
+                format!("Request Queue Full, {} Queries Shed So Far", shed),
+            );
+        }
+        if self.legacy_text_protocol {
+            return Some(Vec::new());
+        }
+        match Message::parse(request) {
+            Ok(query) if !query.header.response => {
+                let mut response = Message::response_to(&query);
+                response.header.rcode = Rcode::ServFail;
+                Some(response.to_bytes_truncated(MAX_UDP_PAYLOAD))
             }
-            Err(TryRecvError::Empty) => {}
-            Err(TryRecvError::Disconnected) => panic!("Channel disconnected"),
+            _ => None,
         }
+    }
+
+    fn reply(&self, reply: &[u8], client_address: SocketAddr) {
+        if let Err(e) = self.server_socket.send_to(reply, client_address) {
+            self.logger.log(
+                LogLevel::Error,
+                format!("Failed to reply to {}: {}", client_address, e),
+            );
+        }
+    }
+
+    /// Answers one wire format query, truncating the answer to `max_len`
+    /// bytes: `MAX_UDP_PAYLOAD` over UDP, `MAX_TCP_PAYLOAD` over TCP.
+    pub fn handle_query(&self, packet: &[u8], max_len: usize) -> Option<Vec<u8>> {
+        let query = match Message::parse(packet) {
+            Ok(query) => query,
+            Err(e) => {
+                self.logger
+                    .log(LogLevel::Debug, format!("Malformed query: {}", e));
+                let id = Message::peek_id(packet)?;
+                return Some(Message::error_response(id, Rcode::FormErr).to_bytes());
+            }
+        };
+        // Never answer responses, otherwise two servers could bounce packets forever
+        if query.header.response {
+            return None;
+        }
+
+        let mut response = Message::response_to(&query);
+        if query.header.opcode != 0 {
+            response.header.rcode = Rcode::NotImp;
+            return Some(response.to_bytes());
+        }
+        if query.questions.len() != 1 {
+            response.header.rcode = Rcode::FormErr;
+            return Some(response.to_bytes());
+        }
+
+        let question = &query.questions[0];
+        if question.qclass != CLASS_IN && question.qclass != CLASS_ANY {
+            response.header.rcode = Rcode::NotImp;
+            return Some(response.to_bytes());
+        }
+        let resolution = self.resolve_dns(&question.name, question.qtype);
+        response.header.rcode = resolution.rcode();
+        if let Resolution::Answer(records) = resolution {
+            response.answers = records;
+        }
+        Some(response.to_bytes_truncated(max_len))
+    }
+
+    /// Answers the queries of one TCP client until it closes the connection,
+    /// stays idle for `TCP_IDLE_TIMEOUT` or the server exits.
+    fn serve_tcp(&self, mut stream: TcpStream) -> io::Result<()> {
+        // Accepted streams may inherit the listener's non-blocking mode
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(EXIT_POLL))?;
+        stream.set_write_timeout(Some(TCP_IDLE_TIMEOUT))?;
+        loop {
+            let deadline = Instant::now() + TCP_IDLE_TIMEOUT;
+            let mut length = [0; 2];
+            if !self.read_tcp(&mut stream, &mut length, deadline)? {
+                return Ok(());
+            }
+            let mut request = vec![0; u16::from_be_bytes(length) as usize];
+            if !self.read_tcp(&mut stream, &mut request, deadline)? {
+                return Ok(());
+            }
+            let Some(reply) = self.handle_query(&request, MAX_TCP_PAYLOAD) else {
+                return Ok(());
+            };
+            let mut framed = Vec::with_capacity(reply.len() + 2);
+            framed.extend_from_slice(&(reply.len() as u16).to_be_bytes());
+            framed.extend_from_slice(&reply);
+            stream.write_all(&framed)?;
+        }
+    }
+
+    /// Fills `buffer` from `stream`, returning false instead when the client
+    /// closed the connection, `deadline` passed or the server is exiting.
+    fn read_tcp(
+        &self,
+        stream: &mut TcpStream,
+        buffer: &mut [u8],
+        deadline: Instant,
+    ) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match stream.read(&mut buffer[filled..]) {
+                Ok(0) => return Ok(false),
+                Ok(received) => filled += received,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if self.is_exited() || Instant::now() >= deadline {
+                        return Ok(false);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn processing_command(self: &Arc<DNSServer>, line: &str) {
+        // Words stay apart so commands can take arguments, which are passed
+        // on as typed so Unicode names reach `DomainName::parse`
+        let input = line.split_whitespace().collect::<Vec<&str>>().join(" ");
 
         if input == "stop" {
             self.logger.log(LogLevel::Warning, "Stop Listening");
@@ -252,7 +1031,18 @@ impl DNSServer {
                 self.logger.log(LogLevel::Warning, "Existed ProcessingRequest not Found. Creating One...");
                 Self::run_processing_request(self);
             }
-        } else if input != "" {
+        } else if input == "flush" {
+            let flushed = self.cache.flush_all();
+            self.logger.log(LogLevel::Warning, format!("Flushed {} Cache Entries", flushed));
+        } else if let Some(name) = input.strip_prefix("flush ") {
+            self.flush_cache(name, false);
+        } else if let Some(suffix) = input.strip_prefix("flush-suffix ") {
+            self.flush_cache(suffix, true);
+        } else if input == "dump" {
+            self.dump_cache();
+        } else if input == "stats" {
+            self.log_stats();
+        } else if !input.is_empty() {
             self.logger.log(
                 LogLevel::Debug,
                 format!("Unknown Command, Receving {}", input),
@@ -260,6 +1050,57 @@ impl DNSServer {
         }
     }
 
+    /// Drops the cached answers for `name`, or for every name under it when
+    /// `suffix` is set.
+    fn flush_cache(&self, name: &str, suffix: bool) {
+        let name = match DomainName::parse(name) {
+            Ok(name) => name,
+            Err(e) => {
+                self.logger.log(LogLevel::Error, format!("Cannot Flush {}: {}", name, e));
+                return;
+            }
+        };
+        // Everything is under the root, `flush` already empties the cache
+        if suffix && name == DomainName::root() {
+            self.logger.log(LogLevel::Error, "Cannot Flush Under the Root, Use flush Instead");
+            return;
+        }
+        let flushed = if suffix {
+            self.cache.flush_suffix(&name)
+        } else {
+            self.cache.flush_name(&name)
+        };
+        let scope = if suffix { "under" } else { "for" };
+        self.logger.log(LogLevel::Warning, format!("Flushed {} Cache Entries {} {}", flushed, scope, self.shown(&name)));
+    }
+
+    fn log_stats(&self) {
+        self.logger.log(LogLevel::Info, &self.outcomes);
+        self.logger.log(LogLevel::Info, &self.stages);
+        self.logger.log(
+            LogLevel::Info,
+            format!(
+                "[Workers] \"Threads\": {}, \"Queue\": {}, Shed: {}, Coalesced: {}.",
+                self.worker_threads,
+                self.request_queue_size,
+                self.shed_queries.load(Ordering::Relaxed),
+                self.upstream_queries.get_coalesced_num()
+            ),
+        );
+        self.logger.log(LogLevel::Info, &self.cache);
+    }
+
+    fn dump_cache(&self) {
+        let entries = self.cache.dump();
+        for (key, answer, remaining) in &entries {
+            self.logger.log(
+                LogLevel::Info,
+                format!("Cache {} {} ({}s left)---->{}", self.shown(&key.name), key.rtype, remaining.as_secs(), format_cached(answer)),
+            );
+        }
+        self.logger.log(LogLevel::Info, format!("Dumped {} Cache Entries", entries.len()));
+    }
+
     pub fn stop(&self) {
         self.stop_request
             .store(true, std::sync::atomic::Ordering::Relaxed);
@@ -271,20 +1112,48 @@ impl DNSServer {
     }
 
     pub fn exit(&self) {
-        self.exit.store(true, std::sync::atomic::Ordering::Relaxed);
+        *self.exit.lock().unwrap() = true;
+        self.exit_signal.notify_all();
     }
 
     pub fn is_exited(&self) -> bool {
-        self.exit.load(std::sync::atomic::Ordering::Relaxed)
+        *self.exit.lock().unwrap()
+    }
+
+    /// Blocks until `exit` is called or `timeout` passes, whichever comes
+    /// first. Returns whether the server is exiting.
+    pub fn wait_for_exit(&self, timeout: Duration) -> bool {
+        let exit = self.exit.lock().unwrap();
+        let (exit, _) = self
+            .exit_signal
+            .wait_timeout_while(exit, timeout, |exit| !*exit)
+            .unwrap();
+        *exit
     }
 
+    /// Receives on this thread and resolves on a pool of `worker_threads`
+    /// workers fed through a queue of `request_queue_size` datagrams.
     pub fn run_processing_request(arc_dns: &Arc<Self>) {
-        let dns_for_handle = Arc::clone(&arc_dns);
-        let handle = thread::spawn(move || loop {
-            if dns_for_handle.is_exited() {
-                break;
-            }
-            dns_for_handle.processing_request();
+        let dns_for_handle = Arc::clone(arc_dns);
+        let handle = thread::spawn(move || {
+            let dns = &*dns_for_handle;
+            let (sender, receiver) = crossbeam_channel::bounded::<Datagram>(dns.request_queue_size);
+            let mut pool = Pool::new(dns.worker_threads as u32);
+            pool.scoped(|scope| {
+                for _ in 0..dns.worker_threads {
+                    let receiver = receiver.clone();
+                    // Ends once the sender is dropped and the queue drained
+                    scope.execute(move || {
+                        for (request, client_address) in receiver {
+                            dns.answer_request(&request, client_address);
+                        }
+                    });
+                }
+                while !dns.is_exited() {
+                    dns.processing_request(&sender);
+                }
+                drop(sender);
+            });
         });
 
         arc_dns.add_handle_record(HandleRecord {
@@ -294,7 +1163,13 @@ impl DNSServer {
         });
         arc_dns
             .logger
-            .log(LogLevel::Info, "Run Processing Request.");
+            .log(
+                LogLevel::Info,
+                format!(
+                    "Run Processing Request with {} Workers, Queue of {}.",
+                    arc_dns.worker_threads, arc_dns.request_queue_size
+                ),
+            );
         if arc_dns
             .handles
             .lock()
@@ -313,15 +1188,174 @@ impl DNSServer {
         }
     }
 
+    /// Accepts TCP clients on the DNS port, each served on its own thread, so
+    /// that answers truncated over UDP can be fetched in full (RFC 7766).
+    pub fn run_processing_tcp(arc_dns: &Arc<Self>) {
+        if arc_dns.tcp_listener.is_none() {
+            return;
+        }
+        let dns_handle = Arc::clone(arc_dns);
+        let handle = thread::spawn(move || {
+            let dns = &*dns_handle;
+            let Some(listener) = &dns.tcp_listener else {
+                return;
+            };
+            let connections = AtomicUsize::new(0);
+            // Connection threads are joined before this thread ends
+            thread::scope(|scope| {
+                while !dns.is_exited() {
+                    if dns.stop_request.load(Ordering::Relaxed) {
+                        // Connections wait in the backlog until listening resumes
+                        dns.wait_for_exit(EXIT_POLL);
+                        continue;
+                    }
+                    let (stream, client_address) = match listener.accept() {
+                        Ok(accepted) => accepted,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            dns.wait_for_exit(EXIT_POLL);
+                            continue;
+                        }
+                        Err(e) => {
+                            dns.logger.log(LogLevel::Error, format!("Failed to accept TCP client: {}", e));
+                            dns.wait_for_exit(EXIT_POLL);
+                            continue;
+                        }
+                    };
+                    if connections.fetch_add(1, Ordering::Relaxed) >= MAX_TCP_CONNECTIONS {
+                        connections.fetch_sub(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    let connections = &connections;
+                    scope.spawn(move || {
+                        if let Err(e) = dns.serve_tcp(stream) {
+                            dns.logger.log(
+                                LogLevel::Debug,
+                                format!("TCP client {} failed: {}", client_address, e),
+                            );
+                        }
+                        connections.fetch_sub(1, Ordering::Relaxed);
+                    });
+                }
+            });
+        });
+
+        arc_dns.add_handle_record(HandleRecord {
+            handle_type: HandleType::ProcessingTcp,
+            logged: false,
+            handle_val: handle,
+        });
+        arc_dns.logger.log(
+            LogLevel::Info,
+            format!(
+                "Run Processing TCP on Port {}, Up to {} Clients.",
+                arc_dns.get_local_port(),
+                MAX_TCP_CONNECTIONS
+            ),
+        );
+    }
+
+    /// Refreshes the popular cache entries `lookup` hands over before they
+    /// expire, off the request path.
+    pub fn run_prefetch(arc_dns: &Arc<Self>) {
+        let dns_handle = Arc::clone(arc_dns);
+        let handle = thread::spawn(move || loop {
+            if dns_handle.is_exited() {
+                break;
+            }
+            let received = dns_handle
+                .prefetch_receiver
+                .lock()
+                .unwrap()
+                .recv_timeout(EXIT_POLL);
+            match received {
+                Ok(key) => dns_handle.prefetch(&key),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        });
+
+        arc_dns.add_handle_record(HandleRecord {
+            handle_type: HandleType::Prefetch,
+            logged: false,
+            handle_val: handle,
+        });
+        arc_dns.logger.log(LogLevel::Info, "Run Prefetch.");
+    }
+
+    fn prefetch(&self, key: &CacheKey) {
+        self.logger.log(
+            LogLevel::Debug,
+            format!("Prefetching {} {}", key.rtype, self.shown(&key.name)),
+        );
+        let upstream = self
+            .upstream_queries
+            .run(key, || self.query_upstreams(&key.name, key.rtype, None));
+        // A refreshed answer replaced the claimed entry, unless it failed or
+        // was not cacheable, and then the next read may try again
+        self.cache.release_prefetch(key);
+        if upstream.is_none() {
+            self.logger.log(
+                LogLevel::Warning,
+                format!("Prefetch failed, domain: {}", self.shown(&key.name)),
+            );
+        }
+    }
+
+    /// Saves the cache every `cache_snapshot_interval`, when a snapshot file
+    /// is configured. The final save happens in `wait_exit`.
+    pub fn run_cache_snapshot(arc_dns: &Arc<Self>) {
+        if arc_dns.cache_snapshot.is_none() || arc_dns.cache_snapshot_interval.is_zero() {
+            return;
+        }
+        let dns_handle = Arc::clone(arc_dns);
+        let handle = thread::spawn(move || {
+            while !dns_handle.wait_for_exit(dns_handle.cache_snapshot_interval) {
+                dns_handle.save_cache_snapshot();
+            }
+        });
+
+        arc_dns.add_handle_record(HandleRecord {
+            handle_type: HandleType::CacheSnapshot,
+            logged: false,
+            handle_val: handle,
+        });
+        arc_dns.logger.log(LogLevel::Info, "Run Cache Snapshot.");
+    }
+
+    fn save_cache_snapshot(&self) {
+        let Some(path) = &self.cache_snapshot else {
+            return;
+        };
+        match snapshot::save(&self.cache, path) {
+            Ok(saved) => self.logger.log(
+                LogLevel::Info,
+                format!("Saved {} Cache Entries to {}", saved, path.display()),
+            ),
+            Err(e) => self.logger.log(
+                LogLevel::Error,
+                format!("Failed to Save Cache Snapshot {}: {}", path.display(), e),
+            ),
+        }
+    }
+
     pub fn run_processing_command(arc_dns: &Arc<Self>) {
-        let mut stdin_channel: std::sync::mpsc::Receiver<String> =
+        let stdin_channel: std::sync::mpsc::Receiver<String> =
             crate::utils::spawn_stdin_channel();
-        let dns_handle = Arc::clone(&arc_dns);
+        let dns_handle = Arc::clone(arc_dns);
         let handle = thread::spawn(move || loop {
             if dns_handle.is_exited() {
                 break;
             }
-            dns_handle.processing_command(&mut stdin_channel);
+            match stdin_channel.recv_timeout(EXIT_POLL) {
+                Ok(line) => dns_handle.processing_command(&line),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    dns_handle
+                        .logger
+                        .log(LogLevel::Warning, "Stdin Closed, Commands Disabled.");
+                    break;
+                }
+            }
             io::stdout().flush().unwrap();
         });
 
@@ -369,7 +1403,7 @@ impl DNSServer {
                 }
             }
             
-            if arc_mutex_dns.is_exited(){
+            if arc_mutex_dns.wait_for_exit(EXIT_POLL) {
                 let mut guard = arc_mutex_dns.handles.lock().unwrap();
                 handles.extend(guard.drain(..));
                 break;
@@ -384,13 +1418,33 @@ impl DNSServer {
             );
         }
 
+        arc_mutex_dns.log_stats();
+        arc_mutex_dns.save_cache_snapshot();
+        arc_mutex_dns.cache.shutdown();
+        arc_mutex_dns.logger.log(LogLevel::Warning, "Cache Sweeper Exited.");
         arc_mutex_dns.logger.log(
             LogLevel::Warning,
-            format!("All Handles Exited. DNS Server Exited."),
+            "All Handles Exited. DNS Server Exited.",
         );
     }
 }
 
+fn format_records(records: &[ResourceRecord]) -> String {
+    records
+        .iter()
+        .map(|record| format!("{} {}", record.rtype, record.data))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn format_cached(answer: &CachedAnswer) -> String {
+    match answer {
+        CachedAnswer::Records(records) => format_records(records),
+        CachedAnswer::NoData => "NODATA".to_string(),
+        CachedAnswer::NXDomain => "NXDOMAIN".to_string(),
+    }
+}
+
 impl fmt::Display for DNSServer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -0,0 +1,163 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+const WILDCARD_LABEL: &str = "*";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DomainError {
+    Empty,
+    EmptyLabel,
+    LabelTooLong(String),
+    NameTooLong,
+    InvalidCharacter(char),
+    MisplacedWildcard,
+    InvalidIdn,
+}
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DomainError::Empty => write!(f, "Domain name is empty"),
+            DomainError::EmptyLabel => write!(f, "Domain name has an empty label"),
+            DomainError::LabelTooLong(label) => {
+                write!(f, "Label {} is longer than {} bytes", label, MAX_LABEL_LEN)
+            }
+            DomainError::NameTooLong => {
+                write!(f, "Domain name is longer than {} bytes", MAX_NAME_LEN)
+            }
+            DomainError::InvalidCharacter(c) => write!(f, "Invalid character {:?}", c),
+            DomainError::MisplacedWildcard => {
+                write!(f, "Wildcard is only allowed as the leftmost label")
+            }
+            DomainError::InvalidIdn => write!(f, "Name cannot be converted to punycode"),
+        }
+    }
+}
+
+impl Error for DomainError {}
+
+/// A validated, lowercased domain name without the trailing dot. The root is
+/// the empty name and is written as `.`.
+///
+/// Labels may contain ASCII letters, digits, `-` and `_` (for SRV style
+/// names); `*` is accepted as a whole leftmost label. Unicode input is
+/// converted to its `xn--` A-label form first, so it matches what clients
+/// send on the wire.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String")]
+pub struct DomainName(String);
+
+impl DomainName {
+    pub fn parse(name: &str) -> Result<DomainName, DomainError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(DomainError::Empty);
+        }
+        let ascii;
+        let name = if name.is_ascii() {
+            name
+        } else {
+            ascii = idna::domain_to_ascii(name).map_err(|_| DomainError::InvalidIdn)?;
+            ascii.as_str()
+        };
+        if name == "." {
+            return Ok(DomainName::root());
+        }
+        let name = name.strip_suffix('.').unwrap_or(name);
+
+        // Each label costs its length plus one length byte, plus the root byte
+        let mut wire_len = 1;
+        for (index, label) in name.split('.').enumerate() {
+            if label.is_empty() {
+                return Err(DomainError::EmptyLabel);
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err(DomainError::LabelTooLong(label.to_string()));
+            }
+            wire_len += label.len() + 1;
+            if label == WILDCARD_LABEL {
+                if index != 0 {
+                    return Err(DomainError::MisplacedWildcard);
+                }
+                continue;
+            }
+            if let Some(c) = label
+                .chars()
+                .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_'))
+            {
+                return Err(match c {
+                    '*' => DomainError::MisplacedWildcard,
+                    _ => DomainError::InvalidCharacter(c),
+                });
+            }
+        }
+        if wire_len > MAX_NAME_LEN {
+            return Err(DomainError::NameTooLong);
+        }
+        Ok(DomainName(name.to_ascii_lowercase()))
+    }
+
+    pub fn root() -> DomainName {
+        DomainName(String::new())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The name with every `xn--` label decoded, for display only.
+    pub fn to_unicode(&self) -> String {
+        if self.is_root() {
+            return ".".to_string();
+        }
+        idna::domain_to_unicode(&self.0).0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Labels from the rightmost (closest to the root) to the leftmost.
+    pub fn labels_reversed(&self) -> impl Iterator<Item = &str> {
+        self.0.rsplit('.').filter(|label| !label.is_empty())
+    }
+
+    /// True for the name itself and every name below it.
+    pub fn is_subdomain_of(&self, parent: &DomainName) -> bool {
+        parent.is_root()
+            || self == parent
+            || self
+                .0
+                .strip_suffix(parent.as_str())
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    }
+}
+
+impl FromStr for DomainName {
+    type Err = DomainError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        DomainName::parse(name)
+    }
+}
+
+impl TryFrom<String> for DomainName {
+    type Error = DomainError;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        DomainName::parse(&name)
+    }
+}
+
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            write!(f, ".")
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
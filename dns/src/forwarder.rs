@@ -0,0 +1,237 @@
+use std::{
+    error::Error,
+    fmt, io,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{
+    domain::DomainName,
+    message::{Message, MessageError, Question, Rcode, RecordType, CLASS_IN},
+};
+
+const DNS_PORT: u16 = 53;
+const MAX_RESPONSE_LEN: usize = 65535;
+const BIND_ATTEMPTS: usize = 8;
+
+#[derive(Debug)]
+pub enum ForwardError {
+    Io(io::Error),
+    Timeout,
+    Malformed(MessageError),
+    Mismatch,
+}
+
+impl fmt::Display for ForwardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardError::Io(e) => write!(f, "Upstream I/O error: {}", e),
+            ForwardError::Timeout => write!(f, "Upstream did not answer in time"),
+            ForwardError::Malformed(e) => write!(f, "Malformed upstream response: {}", e),
+            ForwardError::Mismatch => write!(f, "Upstream answered a different question"),
+        }
+    }
+}
+
+impl Error for ForwardError {}
+
+impl From<io::Error> for ForwardError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ForwardError::Timeout,
+            _ => ForwardError::Io(e),
+        }
+    }
+}
+
+impl From<MessageError> for ForwardError {
+    fn from(e: MessageError) -> Self {
+        ForwardError::Malformed(e)
+    }
+}
+
+/// Sends wire format queries to one upstream server, over UDP first and over
+/// TCP when the UDP answer comes back truncated.
+#[derive(Debug, Clone)]
+pub struct Forwarder {
+    upstream: SocketAddr,
+    timeout: Duration,
+}
+
+impl Forwarder {
+    pub fn new(upstream: SocketAddr, timeout: Duration) -> Self {
+        Forwarder { upstream, timeout }
+    }
+
+    /// Accepts `8.8.8.8`, `8.8.8.8:53`, `::1` or `[::1]:53`.
+    pub fn parse_addr(addr: &str) -> Option<SocketAddr> {
+        addr.parse::<SocketAddr>()
+            .ok()
+            .or_else(|| addr.parse::<IpAddr>().ok().map(|ip| (ip, DNS_PORT).into()))
+    }
+
+    pub fn get_upstream(&self) -> SocketAddr {
+        self.upstream
+    }
+
+    pub fn query(&self, name: &DomainName, qtype: RecordType) -> Result<Message, ForwardError> {
+        let question = Question {
+            name: name.as_str().to_string(),
+            qtype,
+            qclass: CLASS_IN,
+        };
+        let query = Message::query(rand::thread_rng().gen(), question);
+
+        let response = self.query_udp(&query)?;
+        if response.header.truncated {
+            return self.query_tcp(&query);
+        }
+        Ok(response)
+    }
+
+    fn bind_random_port(&self) -> io::Result<UdpSocket> {
+        let local_ip: IpAddr = match self.upstream {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let mut rng = rand::thread_rng();
+        for _ in 0..BIND_ATTEMPTS {
+            if let Ok(socket) = UdpSocket::bind((local_ip, rng.gen_range(1024..=u16::MAX))) {
+                return Ok(socket);
+            }
+        }
+        // Let the OS pick if every random port was taken
+        UdpSocket::bind((local_ip, 0))
+    }
+
+    fn query_udp(&self, query: &Message) -> Result<Message, ForwardError> {
+        let socket = self.bind_random_port()?;
+        // A connected socket drops datagrams from any other address
+        socket.connect(self.upstream)?;
+        socket.send(&query.to_bytes())?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut buffer = vec![0; MAX_RESPONSE_LEN];
+        loop {
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .filter(|remaining| !remaining.is_zero())
+                .ok_or(ForwardError::Timeout)?;
+            socket.set_read_timeout(Some(remaining))?;
+            let received = socket.recv(&mut buffer)?;
+            // Anything that does not answer our question is ignored, so that a
+            // spoofed packet cannot end the wait early
+            match Message::parse(&buffer[..received]) {
+                Ok(response) if Self::answers(query, &response) => return Ok(response),
+                _ => continue,
+            }
+        }
+    }
+
+    fn query_tcp(&self, query: &Message) -> Result<Message, ForwardError> {
+        let mut stream = TcpStream::connect_timeout(&self.upstream, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let bytes = query.to_bytes();
+        let mut framed = Vec::with_capacity(bytes.len() + 2);
+        framed.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        framed.extend_from_slice(&bytes);
+        stream.write_all(&framed)?;
+
+        let mut length = [0; 2];
+        stream.read_exact(&mut length)?;
+        let mut buffer = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut buffer)?;
+
+        let response = Message::parse(&buffer)?;
+        if !Self::answers(query, &response) {
+            return Err(ForwardError::Mismatch);
+        }
+        Ok(response)
+    }
+
+    fn answers(query: &Message, response: &Message) -> bool {
+        response.header.response
+            && response.header.id == query.header.id
+            && response.questions.len() == query.questions.len()
+            && response
+                .questions
+                .iter()
+                .zip(&query.questions)
+                .all(|(answered, asked)| {
+                    answered.name.eq_ignore_ascii_case(&asked.name)
+                        && answered.qtype == asked.qtype
+                        && answered.qclass == asked.qclass
+                })
+    }
+}
+
+/// Queries the nameservers listed in resolv.conf directly, in order, the way
+/// the system stub resolver would.
+#[derive(Debug, Clone)]
+pub struct SystemResolver {
+    forwarders: Vec<Forwarder>,
+}
+
+impl SystemResolver {
+    pub const RESOLV_CONF: &'static str = "/etc/resolv.conf";
+
+    /// Returns `None` when the file lists no usable nameserver.
+    pub fn from_resolv_conf(contents: &str, timeout: Duration) -> Option<SystemResolver> {
+        let forwarders: Vec<Forwarder> = contents
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                match fields.next() {
+                    Some("nameserver") => fields.next(),
+                    _ => None,
+                }
+            })
+            // Drop IPv6 zone indices such as `fe80::1%eth0`
+            .filter_map(|addr| addr.split('%').next())
+            .filter_map(|addr| addr.parse::<IpAddr>().ok())
+            .map(|ip| Forwarder::new((ip, DNS_PORT).into(), timeout))
+            .collect();
+        if forwarders.is_empty() {
+            None
+        } else {
+            Some(SystemResolver { forwarders })
+        }
+    }
+
+    pub fn get_nameservers(&self) -> Vec<SocketAddr> {
+        self.forwarders
+            .iter()
+            .map(Forwarder::get_upstream)
+            .collect()
+    }
+
+    /// Drops nameservers for which `skip` returns true, e.g. ourselves.
+    pub fn without(mut self, skip: impl Fn(&SocketAddr) -> bool) -> Option<SystemResolver> {
+        self.forwarders
+            .retain(|forwarder| !skip(&forwarder.upstream));
+        if self.forwarders.is_empty() {
+            None
+        } else {
+            Some(self)
+        }
+    }
+
+    /// Moves on to the next nameserver on failure, SERVFAIL or REFUSED.
+    pub fn query(&self, name: &DomainName, qtype: RecordType) -> Result<Message, ForwardError> {
+        let mut last = Err(ForwardError::Timeout);
+        for forwarder in &self.forwarders {
+            last = forwarder.query(name, qtype);
+            if let Ok(response) = &last {
+                if matches!(response.header.rcode, Rcode::NoError | Rcode::NXDomain) {
+                    break;
+                }
+            }
+        }
+        last
+    }
+}
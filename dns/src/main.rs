@@ -1,6 +1,12 @@
 mod cache;
+mod coalesce;
 mod dns;
+mod domain;
+mod forwarder;
+mod message;
+mod snapshot;
 mod utils;
+mod zone;
 mod tests;
 use std::sync::Arc;
 
@@ -12,5 +18,8 @@ fn main() {
     let dns_server = Arc::new(DNSServer::new("config.json", LogLevel::Debug));
     DNSServer::run_processing_command(&dns_server);
     DNSServer::run_processing_request(&dns_server);
+    DNSServer::run_processing_tcp(&dns_server);
+    DNSServer::run_prefetch(&dns_server);
+    DNSServer::run_cache_snapshot(&dns_server);
     DNSServer::wait_exit(&dns_server);
 }
@@ -0,0 +1,799 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+pub const MAX_UDP_PAYLOAD: usize = 512;
+/// TCP messages carry a two byte length prefix (RFC 1035 section 4.2.2).
+pub const MAX_TCP_PAYLOAD: usize = u16::MAX as usize;
+pub const CLASS_IN: u16 = 1;
+pub const CLASS_ANY: u16 = 255;
+
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+const MAX_CHARACTER_STRING_LEN: usize = 255;
+const MAX_POINTER_JUMPS: usize = 32;
+const POINTER_MASK: u8 = 0xC0;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageError {
+    UnexpectedEnd,
+    NameTooLong,
+    BadLabelType(u8),
+    PointerLoop,
+    BadRecordData(RecordType),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::UnexpectedEnd => write!(f, "Message ended unexpectedly"),
+            MessageError::NameTooLong => write!(f, "Name longer than {} bytes", MAX_NAME_LEN),
+            MessageError::BadLabelType(byte) => write!(f, "Unsupported label type 0x{:02x}", byte),
+            MessageError::PointerLoop => write!(f, "Too many compression pointers"),
+            MessageError::BadRecordData(rtype) => write!(f, "Malformed {} record data", rtype),
+        }
+    }
+}
+
+impl Error for MessageError {}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    A,
+    NS,
+    CNAME,
+    SOA,
+    PTR,
+    MX,
+    TXT,
+    AAAA,
+    SRV,
+    OPT,
+    ANY,
+    Unknown(u16),
+}
+
+impl From<u16> for RecordType {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => RecordType::A,
+            2 => RecordType::NS,
+            5 => RecordType::CNAME,
+            6 => RecordType::SOA,
+            12 => RecordType::PTR,
+            15 => RecordType::MX,
+            16 => RecordType::TXT,
+            28 => RecordType::AAAA,
+            33 => RecordType::SRV,
+            41 => RecordType::OPT,
+            255 => RecordType::ANY,
+            other => RecordType::Unknown(other),
+        }
+    }
+}
+
+impl From<RecordType> for u16 {
+    fn from(value: RecordType) -> Self {
+        match value {
+            RecordType::A => 1,
+            RecordType::NS => 2,
+            RecordType::CNAME => 5,
+            RecordType::SOA => 6,
+            RecordType::PTR => 12,
+            RecordType::MX => 15,
+            RecordType::TXT => 16,
+            RecordType::AAAA => 28,
+            RecordType::SRV => 33,
+            RecordType::OPT => 41,
+            RecordType::ANY => 255,
+            RecordType::Unknown(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordType::Unknown(value) => write!(f, "TYPE{}", value),
+            known => write!(f, "{:?}", known),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rcode {
+    #[default]
+    NoError,
+    FormErr,
+    ServFail,
+    NXDomain,
+    NotImp,
+    Refused,
+    Unknown(u8),
+}
+
+impl From<u8> for Rcode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Rcode::NoError,
+            1 => Rcode::FormErr,
+            2 => Rcode::ServFail,
+            3 => Rcode::NXDomain,
+            4 => Rcode::NotImp,
+            5 => Rcode::Refused,
+            other => Rcode::Unknown(other),
+        }
+    }
+}
+
+impl From<Rcode> for u8 {
+    fn from(value: Rcode) -> Self {
+        match value {
+            Rcode::NoError => 0,
+            Rcode::FormErr => 1,
+            Rcode::ServFail => 2,
+            Rcode::NXDomain => 3,
+            Rcode::NotImp => 4,
+            Rcode::Refused => 5,
+            Rcode::Unknown(other) => other & 0x0F,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Header {
+    pub id: u16,
+    pub response: bool,
+    pub opcode: u8,
+    pub authoritative: bool,
+    pub truncated: bool,
+    pub recursion_desired: bool,
+    pub recursion_available: bool,
+    pub authentic_data: bool,
+    pub checking_disabled: bool,
+    pub rcode: Rcode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: RecordType,
+    pub qclass: u16,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    CNAME(String),
+    NS(String),
+    PTR(String),
+    MX {
+        preference: u16,
+        exchange: String,
+    },
+    TXT(Vec<String>),
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    SOA {
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    Unknown(Vec<u8>),
+}
+
+impl RData {
+    /// Parses the zone file presentation form of `rtype`'s data, e.g.
+    /// `10 mail.example.com` for MX or `0 5 5060 sip.example.com` for SRV.
+    pub fn from_presentation(rtype: RecordType, value: &str) -> Result<RData, MessageError> {
+        let bad = || MessageError::BadRecordData(rtype);
+        let fields: Vec<&str> = value.split_whitespace().collect();
+        let name = |index: usize| -> Result<String, MessageError> {
+            let field = fields.get(index).ok_or_else(bad)?;
+            Ok(field.trim_end_matches('.').to_string())
+        };
+        let number = |index: usize| -> Result<u32, MessageError> {
+            fields
+                .get(index)
+                .ok_or_else(bad)?
+                .parse()
+                .map_err(|_| bad())
+        };
+        let short = |index: usize| -> Result<u16, MessageError> {
+            fields
+                .get(index)
+                .ok_or_else(bad)?
+                .parse()
+                .map_err(|_| bad())
+        };
+
+        let expected_fields = match rtype {
+            RecordType::A | RecordType::AAAA => 1,
+            RecordType::CNAME | RecordType::NS | RecordType::PTR => 1,
+            RecordType::MX => 2,
+            RecordType::SRV => 4,
+            RecordType::SOA => 7,
+            RecordType::TXT => return Ok(RData::TXT(parse_character_strings(value))),
+            _ => return Err(bad()),
+        };
+        if fields.len() != expected_fields {
+            return Err(bad());
+        }
+
+        let data = match rtype {
+            RecordType::A => RData::A(value.trim().parse().map_err(|_| bad())?),
+            RecordType::AAAA => RData::AAAA(value.trim().parse().map_err(|_| bad())?),
+            RecordType::CNAME => RData::CNAME(name(0)?),
+            RecordType::NS => RData::NS(name(0)?),
+            RecordType::PTR => RData::PTR(name(0)?),
+            RecordType::MX => RData::MX {
+                preference: short(0)?,
+                exchange: name(1)?,
+            },
+            RecordType::SRV => RData::SRV {
+                priority: short(0)?,
+                weight: short(1)?,
+                port: short(2)?,
+                target: name(3)?,
+            },
+            _ => RData::SOA {
+                mname: name(0)?,
+                rname: name(1)?,
+                serial: number(2)?,
+                refresh: number(3)?,
+                retry: number(4)?,
+                expire: number(5)?,
+                minimum: number(6)?,
+            },
+        };
+        Ok(data)
+    }
+}
+
+/// Splits TXT data into its character strings. Quoted input (as found in zone
+/// files and DoH answers) yields one string per quoted part; anything else is
+/// taken verbatim and chunked to the 255 byte limit.
+fn parse_character_strings(value: &str) -> Vec<String> {
+    let value = value.trim();
+    if !value.starts_with('"') {
+        return chunk_character_string(value);
+    }
+
+    let mut strings = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                strings.extend(chunk_character_string(&current));
+                current.clear();
+                quoted = false;
+            }
+            '"' => quoted = true,
+            '\\' if quoted => current.extend(chars.next()),
+            _ if quoted => current.push(c),
+            _ => {}
+        }
+    }
+    if quoted {
+        strings.extend(chunk_character_string(&current));
+    }
+    strings
+}
+
+fn chunk_character_string(value: &str) -> Vec<String> {
+    if value.is_empty() {
+        return vec![String::new()];
+    }
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for c in value.chars() {
+        if current.len() + c.len_utf8() > MAX_CHARACTER_STRING_LEN {
+            chunks.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+    }
+    chunks.push(current);
+    chunks
+}
+
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(ip) => write!(f, "{}", ip),
+            RData::AAAA(ip) => write!(f, "{}", ip),
+            RData::CNAME(name) | RData::NS(name) | RData::PTR(name) => write!(f, "{}.", name),
+            RData::MX {
+                preference,
+                exchange,
+            } => write!(f, "{} {}.", preference, exchange),
+            RData::TXT(strings) => {
+                let quoted: Vec<String> = strings.iter().map(|s| format!("{:?}", s)).collect();
+                write!(f, "{}", quoted.join(" "))
+            }
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "{} {} {} {}.", priority, weight, port, target),
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => write!(
+                f,
+                "{}. {}. {} {} {} {} {}",
+                mname, rname, serial, refresh, retry, expire, minimum
+            ),
+            RData::Unknown(bytes) => write!(f, "\\# {}", bytes.len()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceRecord {
+    pub name: String,
+    pub rtype: RecordType,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub header: Header,
+    pub questions: Vec<Question>,
+    pub answers: Vec<ResourceRecord>,
+    pub authorities: Vec<ResourceRecord>,
+    pub additionals: Vec<ResourceRecord>,
+}
+
+impl Message {
+    pub fn parse(packet: &[u8]) -> Result<Message, MessageError> {
+        let mut reader = Reader::new(packet);
+        let id = reader.read_u16()?;
+        let flags = reader.read_u16()?;
+        let qdcount = reader.read_u16()?;
+        let ancount = reader.read_u16()?;
+        let nscount = reader.read_u16()?;
+        let arcount = reader.read_u16()?;
+
+        let header = Header {
+            id,
+            response: flags & 0x8000 != 0,
+            opcode: ((flags >> 11) & 0x0F) as u8,
+            authoritative: flags & 0x0400 != 0,
+            truncated: flags & 0x0200 != 0,
+            recursion_desired: flags & 0x0100 != 0,
+            recursion_available: flags & 0x0080 != 0,
+            authentic_data: flags & 0x0020 != 0,
+            checking_disabled: flags & 0x0010 != 0,
+            rcode: Rcode::from((flags & 0x000F) as u8),
+        };
+
+        let mut questions = Vec::with_capacity(qdcount.min(16) as usize);
+        for _ in 0..qdcount {
+            let name = reader.read_name()?;
+            let qtype = RecordType::from(reader.read_u16()?);
+            let qclass = reader.read_u16()?;
+            questions.push(Question {
+                name,
+                qtype,
+                qclass,
+            });
+        }
+
+        Ok(Message {
+            header,
+            questions,
+            answers: reader.read_records(ancount)?,
+            authorities: reader.read_records(nscount)?,
+            additionals: reader.read_records(arcount)?,
+        })
+    }
+
+    /// Reads only the transaction ID, so that a reply can still be sent for
+    /// packets that fail to parse.
+    pub fn peek_id(packet: &[u8]) -> Option<u16> {
+        Reader::new(packet).read_u16().ok()
+    }
+
+    pub fn query(id: u16, question: Question) -> Message {
+        Message {
+            header: Header {
+                id,
+                recursion_desired: true,
+                ..Header::default()
+            },
+            questions: vec![question],
+            ..Message::default()
+        }
+    }
+
+    pub fn response_to(query: &Message) -> Message {
+        Message {
+            header: Header {
+                id: query.header.id,
+                response: true,
+                opcode: query.header.opcode,
+                recursion_desired: query.header.recursion_desired,
+                recursion_available: true,
+                checking_disabled: query.header.checking_disabled,
+                ..Header::default()
+            },
+            questions: query.questions.clone(),
+            ..Message::default()
+        }
+    }
+
+    pub fn error_response(id: u16, rcode: Rcode) -> Message {
+        Message {
+            header: Header {
+                id,
+                response: true,
+                recursion_available: true,
+                rcode,
+                ..Header::default()
+            },
+            ..Message::default()
+        }
+    }
+
+    /// How long a NXDOMAIN or NODATA answer may be cached: the smaller of the
+    /// authority SOA's own TTL and its MINIMUM field (RFC 2308 section 5).
+    pub fn negative_ttl(&self) -> Option<u32> {
+        self.authorities.iter().find_map(|record| match record.data {
+            RData::SOA { minimum, .. } => Some(record.ttl.min(minimum)),
+            _ => None,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        let header = &self.header;
+        let mut flags = ((header.opcode as u16) & 0x0F) << 11 | u8::from(header.rcode) as u16;
+        for (set, bit) in [
+            (header.response, 0x8000),
+            (header.authoritative, 0x0400),
+            (header.truncated, 0x0200),
+            (header.recursion_desired, 0x0100),
+            (header.recursion_available, 0x0080),
+            (header.authentic_data, 0x0020),
+            (header.checking_disabled, 0x0010),
+        ] {
+            if set {
+                flags |= bit;
+            }
+        }
+
+        writer.write_u16(header.id);
+        writer.write_u16(flags);
+        writer.write_u16(self.questions.len() as u16);
+        writer.write_u16(self.answers.len() as u16);
+        writer.write_u16(self.authorities.len() as u16);
+        writer.write_u16(self.additionals.len() as u16);
+
+        for question in &self.questions {
+            writer.write_name(&question.name);
+            writer.write_u16(question.qtype.into());
+            writer.write_u16(question.qclass);
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            writer.write_record(record);
+        }
+        writer.buf
+    }
+
+    /// Encodes the message, dropping every record section and setting the TC
+    /// bit if the result would not fit in `max_len` bytes.
+    pub fn to_bytes_truncated(&self, max_len: usize) -> Vec<u8> {
+        let bytes = self.to_bytes();
+        if bytes.len() <= max_len {
+            return bytes;
+        }
+        let mut truncated = Message {
+            header: self.header.clone(),
+            questions: self.questions.clone(),
+            ..Message::default()
+        };
+        truncated.header.truncated = true;
+        truncated.to_bytes()
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], MessageError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or(MessageError::UnexpectedEnd)?;
+        let bytes = self
+            .buf
+            .get(self.pos..end)
+            .ok_or(MessageError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, MessageError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, MessageError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_name(&mut self) -> Result<String, MessageError> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut wire_len = 1;
+        let mut jumps = 0;
+        let mut cursor = self.pos;
+        let mut resume_at = None;
+
+        loop {
+            let len = *self.buf.get(cursor).ok_or(MessageError::UnexpectedEnd)?;
+            match len & POINTER_MASK {
+                0x00 => {
+                    cursor += 1;
+                    if len == 0 {
+                        break;
+                    }
+                    let len = len as usize;
+                    wire_len += len + 1;
+                    if wire_len > MAX_NAME_LEN {
+                        return Err(MessageError::NameTooLong);
+                    }
+                    let label = self
+                        .buf
+                        .get(cursor..cursor + len)
+                        .ok_or(MessageError::UnexpectedEnd)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    cursor += len;
+                }
+                POINTER_MASK => {
+                    let low = *self
+                        .buf
+                        .get(cursor + 1)
+                        .ok_or(MessageError::UnexpectedEnd)?;
+                    if resume_at.is_none() {
+                        resume_at = Some(cursor + 2);
+                    }
+                    jumps += 1;
+                    if jumps > MAX_POINTER_JUMPS {
+                        return Err(MessageError::PointerLoop);
+                    }
+                    cursor = (((len & !POINTER_MASK) as usize) << 8) | low as usize;
+                }
+                _ => return Err(MessageError::BadLabelType(len)),
+            }
+        }
+
+        self.pos = resume_at.unwrap_or(cursor);
+        Ok(labels.join("."))
+    }
+
+    fn read_records(&mut self, count: u16) -> Result<Vec<ResourceRecord>, MessageError> {
+        let mut records = Vec::with_capacity(count.min(16) as usize);
+        for _ in 0..count {
+            records.push(self.read_record()?);
+        }
+        Ok(records)
+    }
+
+    fn read_record(&mut self) -> Result<ResourceRecord, MessageError> {
+        let name = self.read_name()?;
+        let rtype = RecordType::from(self.read_u16()?);
+        let class = self.read_u16()?;
+        let ttl = self.read_u32()?;
+        let rdlength = self.read_u16()? as usize;
+        let start = self.pos;
+        let rdata = self.read_bytes(rdlength)?;
+        let end = self.pos;
+        let bad = || MessageError::BadRecordData(rtype);
+
+        // Names inside RDATA may point anywhere in the message, so they are
+        // read in place rather than from the `rdata` slice.
+        self.pos = start;
+        let data = match rtype {
+            RecordType::A => {
+                let octets: [u8; 4] = rdata.try_into().map_err(|_| bad())?;
+                RData::A(Ipv4Addr::from(octets))
+            }
+            RecordType::AAAA => {
+                let octets: [u8; 16] = rdata.try_into().map_err(|_| bad())?;
+                RData::AAAA(Ipv6Addr::from(octets))
+            }
+            RecordType::CNAME => RData::CNAME(self.read_name()?),
+            RecordType::NS => RData::NS(self.read_name()?),
+            RecordType::PTR => RData::PTR(self.read_name()?),
+            RecordType::MX => RData::MX {
+                preference: self.read_u16()?,
+                exchange: self.read_name()?,
+            },
+            RecordType::TXT => {
+                let mut strings = Vec::new();
+                let mut rest = rdata;
+                while let Some((&len, tail)) = rest.split_first() {
+                    let text = tail.get(..len as usize).ok_or_else(bad)?;
+                    strings.push(String::from_utf8_lossy(text).into_owned());
+                    rest = &tail[len as usize..];
+                }
+                RData::TXT(strings)
+            }
+            RecordType::SRV => RData::SRV {
+                priority: self.read_u16()?,
+                weight: self.read_u16()?,
+                port: self.read_u16()?,
+                target: self.read_name()?,
+            },
+            RecordType::SOA => RData::SOA {
+                mname: self.read_name()?,
+                rname: self.read_name()?,
+                serial: self.read_u32()?,
+                refresh: self.read_u32()?,
+                retry: self.read_u32()?,
+                expire: self.read_u32()?,
+                minimum: self.read_u32()?,
+            },
+            _ => RData::Unknown(rdata.to_vec()),
+        };
+        if !matches!(
+            data,
+            RData::A(_) | RData::AAAA(_) | RData::TXT(_) | RData::Unknown(_)
+        ) && self.pos != end
+        {
+            return Err(bad());
+        }
+        self.pos = end;
+
+        Ok(ResourceRecord {
+            name,
+            rtype,
+            class,
+            ttl,
+            data,
+        })
+    }
+}
+
+struct Writer {
+    buf: Vec<u8>,
+    names: HashMap<String, u16>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Writer {
+            buf: Vec::with_capacity(MAX_UDP_PAYLOAD),
+            names: HashMap::new(),
+        }
+    }
+
+    fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn write_name(&mut self, name: &str) {
+        self.write_name_compressed(name, true)
+    }
+
+    fn write_name_compressed(&mut self, name: &str, compress: bool) {
+        let name = name.trim_end_matches('.');
+        let labels: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+
+        for index in 0..labels.len() {
+            let suffix = labels[index..].join(".").to_ascii_lowercase();
+            if let Some(offset) = self.names.get(&suffix).filter(|_| compress) {
+                self.write_u16(0xC000 | offset);
+                return;
+            }
+            // Offsets beyond 14 bits cannot be the target of a pointer.
+            if self.buf.len() < 0x4000 {
+                self.names.insert(suffix, self.buf.len() as u16);
+            }
+            let label = &labels[index].as_bytes()[..labels[index].len().min(MAX_LABEL_LEN)];
+            self.buf.push(label.len() as u8);
+            self.buf.extend_from_slice(label);
+        }
+        self.buf.push(0);
+    }
+
+    fn write_record(&mut self, record: &ResourceRecord) {
+        self.write_name(&record.name);
+        self.write_u16(record.rtype.into());
+        self.write_u16(record.class);
+        self.write_u32(record.ttl);
+
+        let length_at = self.buf.len();
+        self.write_u16(0);
+        match &record.data {
+            RData::A(ip) => self.buf.extend_from_slice(&ip.octets()),
+            RData::AAAA(ip) => self.buf.extend_from_slice(&ip.octets()),
+            RData::CNAME(name) | RData::NS(name) | RData::PTR(name) => self.write_name(name),
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                self.write_u16(*preference);
+                self.write_name(exchange);
+            }
+            RData::TXT(strings) => {
+                for text in strings {
+                    let text = &text.as_bytes()[..text.len().min(MAX_CHARACTER_STRING_LEN)];
+                    self.buf.push(text.len() as u8);
+                    self.buf.extend_from_slice(text);
+                }
+            }
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                self.write_u16(*priority);
+                self.write_u16(*weight);
+                self.write_u16(*port);
+                // RFC 2782 forbids compressing the SRV target
+                self.write_name_compressed(target, false);
+            }
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                self.write_name(mname);
+                self.write_name(rname);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    self.write_u32(*value);
+                }
+            }
+            RData::Unknown(bytes) => self.buf.extend_from_slice(bytes),
+        }
+        let rdlength = (self.buf.len() - length_at - 2) as u16;
+        self.buf[length_at..length_at + 2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{Cache, CacheKey, CachedAnswer},
+    domain::DomainName,
+    message::Message,
+};
+
+/// Bumped whenever the layout of `SnapshotEntry` changes; older versions
+/// keep their own reader in `load`.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "Snapshot I/O error: {}", e),
+            SnapshotError::Json(e) => write!(f, "Invalid snapshot JSON: {}", e),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported snapshot version {}", version)
+            }
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(e: serde_json::Error) -> Self {
+        SnapshotError::Json(e)
+    }
+}
+
+/// Only the version is read first, to pick the reader for the rest.
+#[derive(Deserialize)]
+struct SnapshotHeader {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    entries: Vec<SnapshotEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    name: DomainName,
+    #[serde(rename = "type")]
+    rtype: u16,
+    class: u16,
+    /// Seconds since the Unix epoch, so the entry means the same after a
+    /// restart.
+    expires_at: u64,
+    answer: SnapshotAnswer,
+}
+
+/// Record sets are kept in wire format, hex encoded, so every record type
+/// survives the round trip.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SnapshotAnswer {
+    Records(String),
+    NoData,
+    NxDomain,
+}
+
+impl SnapshotEntry {
+    fn new(key: CacheKey, answer: CachedAnswer, expire_time: SystemTime) -> SnapshotEntry {
+        let answer = match answer {
+            CachedAnswer::Records(answers) => {
+                let message = Message {
+                    answers,
+                    ..Message::default()
+                };
+                SnapshotAnswer::Records(to_hex(&message.to_bytes()))
+            }
+            CachedAnswer::NoData => SnapshotAnswer::NoData,
+            CachedAnswer::NXDomain => SnapshotAnswer::NxDomain,
+        };
+        SnapshotEntry {
+            name: key.name,
+            rtype: key.rtype.into(),
+            class: key.class,
+            expires_at: expire_time
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            answer,
+        }
+    }
+
+    /// `None` if the record data no longer parses or the expiry time is out
+    /// of range.
+    fn into_parts(self) -> Option<(CacheKey, CachedAnswer, SystemTime)> {
+        let answer = match self.answer {
+            SnapshotAnswer::Records(hex) => {
+                let message = Message::parse(&from_hex(&hex)?).ok()?;
+                CachedAnswer::Records(message.answers)
+            }
+            SnapshotAnswer::NoData => CachedAnswer::NoData,
+            SnapshotAnswer::NxDomain => CachedAnswer::NXDomain,
+        };
+        let key = CacheKey {
+            name: self.name,
+            rtype: self.rtype.into(),
+            class: self.class,
+        };
+        let expire_time = UNIX_EPOCH.checked_add(Duration::from_secs(self.expires_at))?;
+        Some((key, answer, expire_time))
+    }
+}
+
+/// Writes every fresh cache entry to `path`, through a temporary file so a
+/// crash mid-write never leaves a truncated snapshot. Returns the entry count.
+pub fn save(cache: &Cache, path: &Path) -> Result<usize, SnapshotError> {
+    let entries: Vec<SnapshotEntry> = cache
+        .export()
+        .into_iter()
+        .map(|(key, answer, expire_time)| SnapshotEntry::new(key, answer, expire_time))
+        .collect();
+    let count = entries.len();
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        entries,
+    };
+
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, serde_json::to_vec(&snapshot)?)?;
+    fs::rename(&temp_path, path)?;
+    Ok(count)
+}
+
+/// Loads the snapshot at `path` into `cache`, dropping entries that expired
+/// while the server was down. Returns how many entries were restored.
+pub fn load(cache: &Cache, path: &Path) -> Result<usize, SnapshotError> {
+    let contents = fs::read(path)?;
+    let header: SnapshotHeader = serde_json::from_slice(&contents)?;
+    let entries = match header.version {
+        1 => serde_json::from_slice::<Snapshot>(&contents)?.entries,
+        version => return Err(SnapshotError::UnsupportedVersion(version)),
+    };
+    let restored = entries
+        .into_iter()
+        .filter_map(SnapshotEntry::into_parts)
+        .map(|(key, answer, expire_time)| cache.restore(key, answer, expire_time))
+        .filter(|restored| *restored)
+        .count();
+    Ok(restored)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
@@ -2,10 +2,47 @@
 #[cfg(test)]
 use core::time;
 #[cfg(test)]
-use std::thread::sleep;
-#[cfg(test)]
 use super::*;
 
+/// Cache key for `name` and `rtype` in class IN.
+#[cfg(test)]
+fn cache_key(name: &str, rtype: message::RecordType) -> cache::CacheKey {
+    cache::CacheKey { name: name.parse().unwrap(), rtype, class: message::CLASS_IN }
+}
+
+/// One class IN record.
+#[cfg(test)]
+fn record(name: &str, rtype: message::RecordType, ttl: u32, data: message::RData) -> message::ResourceRecord {
+    message::ResourceRecord { name: name.to_string(), rtype, class: message::CLASS_IN, ttl, data }
+}
+
+/// A single A record for `name`, as a cacheable record set.
+#[cfg(test)]
+fn a_records(name: &str, ttl: u32) -> Vec<message::ResourceRecord> {
+    vec![record(name, message::RecordType::A, ttl, message::RData::A("10.0.0.1".parse().unwrap()))]
+}
+
+/// A server on an ephemeral port answering from `zone`, with forwarding to
+/// `remote_dns_addr` disabled. `config` overrides the other settings.
+#[cfg(test)]
+fn test_server(name: &str, zone: &str, config: serde_json::Value) -> dns::DNSServer {
+    let dir = std::env::temp_dir().join(format!("dns-test-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let zone_path = dir.join("dns_config.json");
+    std::fs::write(&zone_path, zone).unwrap();
+    let mut full = serde_json::json!({
+        "dns_config": zone_path,
+        "dns_port": 0,
+        "remote_dns_addr": "",
+        "name": name,
+        "cache_time": 5,
+    });
+    full.as_object_mut().unwrap().extend(config.as_object().unwrap().clone());
+    let config_path = dir.join("config.json");
+    std::fs::write(&config_path, full.to_string()).unwrap();
+    dns::DNSServer::new(config_path.to_str().unwrap(), log::LogLevel::Error)
+}
+
 #[test]
 fn test_log() {
     let logger = log::Logger::new("MyLogger", log::LogLevel::Debug);
@@ -21,30 +58,1052 @@ fn test_utils(){
     let logger = log::Logger::new("MyLogger", log::LogLevel::Debug);
     let json_data = utils::_read_json_file("../config.json").expect("Failed to read JSON file");
     logger.log(log::LogLevel::Debug, format!("JSON data: {:?}", json_data));
-
-    let shell_output =
-        utils::exec_shell_command("echo Hello, World!").expect("Failed to execute shell command");
-    logger.log(log::LogLevel::Debug, shell_output.clone());
-    assert_eq!(shell_output, "Hello, World!\n");
 }
 
 #[test]
 fn test_cache(){
     let logger = log::Logger::new("MyLogger", log::LogLevel::Debug);
-    let cache = cache::Cache::new(1);
-    cache.put("binciluo".to_string(), "127.0.0.1".to_string());
-    let key = "binciluo".to_string();
-    if let Some(ip) = cache.get(key.clone().as_str(), false) {
-        logger.log(log::LogLevel::Info, format!("Got {}---->{}", key, ip))
+    let clock = std::sync::Arc::new(::cache::ManualClock::new());
+    let cache = cache::Cache::with_clock(0, 2, cache::CacheLimits::default(), clock.clone());
+    let name: domain::DomainName = "binciluo".parse().unwrap();
+    let key = cache::CacheKey { name: name.clone(), rtype: message::RecordType::A, class: message::CLASS_IN };
+    let record = message::ResourceRecord {
+        name: name.to_string(),
+        rtype: message::RecordType::A,
+        class: message::CLASS_IN,
+        ttl: 300,
+        data: message::RData::A("127.0.0.1".parse().unwrap()),
+    };
+    cache.put(key.clone(), vec![record.clone()]);
+    // Never read, so only sweeping can remove it
+    let unread = cache::CacheKey { rtype: message::RecordType::AAAA, ..key.clone() };
+    cache.put(unread, vec![record]);
+    if let Some(cache::CachedAnswer::Records(records)) = cache.get(&key) {
+        logger.log(log::LogLevel::Info, format!("Got {}---->{}", name, records[0].data));
+        // Clamped to the 2s maximum
+        assert_eq!(records[0].ttl, 2);
     } else {
-        logger.log(log::LogLevel::Warning, format!("Unfound {}", key.clone()));
+        logger.log(log::LogLevel::Warning, format!("Unfound {}", name.clone()));
         panic!("Should be found.");
     };
-    sleep(time::Duration::from_secs(1));
-    if let Some(ip) = cache.get(key.clone().as_str(), false) {
-        logger.log(log::LogLevel::Info, format!("Got {}---->{}", key, ip));
-        panic!("Should not be found. It shoud be removed from cache after 1s.");
+    // Reported as time remaining
+    clock.advance(time::Duration::from_secs(1));
+    let Some(cache::CachedAnswer::Records(records)) = cache.get(&key) else {
+        panic!("Should be found.");
+    };
+    assert_eq!(records[0].ttl, 1);
+    clock.advance(time::Duration::from_secs(1));
+    if let Some(answer) = cache.get(&key) {
+        logger.log(log::LogLevel::Info, format!("Got {}---->{:?}", name, answer));
+        panic!("Should not be found. It shoud be removed from cache after 2s.");
     } else {
-        logger.log(log::LogLevel::Warning, format!("Unfound {}", key.clone()))
+        logger.log(log::LogLevel::Warning, format!("Unfound {}", name.clone()))
+    };
+    // Swept now instead of whenever the sweeper thread next wakes in real
+    // time, which the `cache` crate tests on its own
+    cache.sweep();
+    assert_eq!(cache.get_record_num(), 0);
+}
+
+#[test]
+fn test_cache_key(){
+    let cache = cache::Cache::new(60, 3600, cache::CacheLimits::default());
+
+    // Each type has its own entry and its own lifetime
+    cache.put(cache_key("www.local.com", message::RecordType::A), vec![
+        record("www.local.com", message::RecordType::A, 300, message::RData::A("10.0.0.1".parse().unwrap())),
+        record("www.local.com", message::RecordType::A, 120, message::RData::A("10.0.0.2".parse().unwrap())),
+    ]);
+    cache.put(cache_key("www.local.com", message::RecordType::AAAA), vec![
+        record("www.local.com", message::RecordType::AAAA, 5, message::RData::AAAA("::1".parse().unwrap())),
+    ]);
+    assert_eq!(cache.get_record_num(), 2);
+    assert!(cache.get(&cache_key("www.local.com", message::RecordType::MX)).is_none());
+    assert!(cache.get(&cache::CacheKey { class: message::CLASS_ANY, ..cache_key("www.local.com", message::RecordType::A) }).is_none());
+
+    // The smallest TTL of the set wins, and counts down from there
+    let Some(cache::CachedAnswer::Records(a)) = cache.get(&cache_key("www.local.com", message::RecordType::A)) else {
+        panic!("Should be found.");
+    };
+    assert_eq!(a.len(), 2);
+    assert!(a.iter().all(|record| record.ttl <= 120 && record.ttl >= 118));
+    // Short TTLs are raised to the minimum
+    let Some(cache::CachedAnswer::Records(aaaa)) = cache.get(&cache_key("www.local.com", message::RecordType::AAAA)) else {
+        panic!("Should be found.");
+    };
+    assert!(aaaa[0].ttl >= 58 && aaaa[0].ttl <= 60);
+}
+
+#[test]
+fn test_cache_negative(){
+    let cache = cache::Cache::new(0, 3600, cache::CacheLimits::default());
+
+    cache.put_negative(cache_key("missing.local.com", message::RecordType::A), cache::CachedAnswer::NXDomain, 60);
+    cache.put_negative(cache_key("www.local.com", message::RecordType::MX), cache::CachedAnswer::NoData, 60);
+    assert_eq!(cache.get(&cache_key("missing.local.com", message::RecordType::A)), Some(cache::CachedAnswer::NXDomain));
+    assert_eq!(cache.get(&cache_key("www.local.com", message::RecordType::MX)), Some(cache::CachedAnswer::NoData));
+    // A negative entry for one type says nothing about the others
+    assert!(cache.get(&cache_key("www.local.com", message::RecordType::A)).is_none());
+    assert_eq!(cache.get_negative_num(), 2);
+    assert!(format!("{}", cache).contains("Negative Num: 2"));
+
+    // A zero TTL is not kept at all
+    cache.put_negative(cache_key("gone.local.com", message::RecordType::A), cache::CachedAnswer::NXDomain, 0);
+    assert!(cache.get(&cache_key("gone.local.com", message::RecordType::A)).is_none());
+
+    // The SOA in the authority section bounds the negative TTL
+    let soa = |ttl, minimum| message::ResourceRecord {
+        name: "local.com".to_string(),
+        rtype: message::RecordType::SOA,
+        class: message::CLASS_IN,
+        ttl,
+        data: message::RData::SOA {
+            mname: "ns.local.com".to_string(),
+            rname: "admin.local.com".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+        },
+    };
+    let mut response = message::Message::error_response(1, message::Rcode::NXDomain);
+    assert_eq!(response.negative_ttl(), None);
+    response.authorities.push(soa(900, 60));
+    assert_eq!(response.negative_ttl(), Some(60));
+    response.authorities[0] = soa(30, 60);
+    assert_eq!(response.negative_ttl(), Some(30));
+}
+
+#[test]
+fn test_cache_eviction(){
+    let limits = |policy| cache::CacheLimits { max_entries: 2, policy, ..Default::default() };
+
+    // LRU: reading "a" makes "b" the oldest
+    let lru = cache::Cache::new(0, 3600, limits(cache::EvictionPolicy::Lru));
+    lru.put(cache_key("a.local.com", message::RecordType::A), a_records("a.local.com", 300));
+    lru.put(cache_key("b.local.com", message::RecordType::A), a_records("b.local.com", 300));
+    assert!(lru.get(&cache_key("a.local.com", message::RecordType::A)).is_some());
+    lru.put(cache_key("c.local.com", message::RecordType::A), a_records("c.local.com", 300));
+    assert_eq!(lru.get_record_num(), 2);
+    assert_eq!(lru.stats().evictions, 1);
+    assert!(lru.get(&cache_key("b.local.com", message::RecordType::A)).is_none());
+    assert!(lru.get(&cache_key("a.local.com", message::RecordType::A)).is_some());
+    assert!(format!("{}", lru).contains("Evictions: 1"));
+
+    // LFU: "a" is read twice, "b" once and more recently, so "b" goes
+    let lfu = cache::Cache::new(0, 3600, limits(cache::EvictionPolicy::Lfu));
+    lfu.put(cache_key("a.local.com", message::RecordType::A), a_records("a.local.com", 300));
+    lfu.put(cache_key("b.local.com", message::RecordType::A), a_records("b.local.com", 300));
+    lfu.get(&cache_key("a.local.com", message::RecordType::A));
+    lfu.get(&cache_key("a.local.com", message::RecordType::A));
+    lfu.get(&cache_key("b.local.com", message::RecordType::A));
+    lfu.put(cache_key("c.local.com", message::RecordType::A), a_records("c.local.com", 300));
+    assert!(lfu.get(&cache_key("b.local.com", message::RecordType::A)).is_none());
+    assert!(lfu.get(&cache_key("a.local.com", message::RecordType::A)).is_some());
+    assert!(lfu.get(&cache_key("c.local.com", message::RecordType::A)).is_some());
+
+    // Overwriting a key never evicts another one
+    lfu.put(cache_key("c.local.com", message::RecordType::A), a_records("c.local.com", 300));
+    assert_eq!(lfu.stats().evictions, 1);
+
+    // The byte budget holds as well as the entry count
+    let unbounded = cache::Cache::new(0, 3600, cache::CacheLimits::default());
+    unbounded.put(cache_key("a.local.com", message::RecordType::A), a_records("a.local.com", 300));
+    let entry_bytes = unbounded.get_byte_num();
+    let bytes = cache::Cache::new(0, 3600, cache::CacheLimits {
+        max_entries: 100,
+        max_bytes: Some(entry_bytes * 3),
+        ..Default::default()
+    });
+    for name in ["a.local.com", "b.local.com", "c.local.com", "d.local.com", "e.local.com"] {
+        bytes.put(cache_key(name, message::RecordType::A), a_records(name, 300));
+        assert!(bytes.get_byte_num() <= entry_bytes * 3);
+    }
+    assert_eq!(bytes.get_record_num(), 3);
+    assert_eq!(bytes.stats().evictions, 2);
+}
+
+#[test]
+fn test_cache_stale(){
+    let clock = std::sync::Arc::new(::cache::ManualClock::new());
+    let stale = cache::Cache::with_clock(0, 3600, cache::CacheLimits::default(), clock.clone())
+        .with_stale_window(time::Duration::from_secs(60));
+    let strict = cache::Cache::with_clock(0, 3600, cache::CacheLimits::default(), clock.clone());
+    stale.put(cache_key("www.local.com", message::RecordType::A), a_records("www.local.com", 1));
+    strict.put(cache_key("www.local.com", message::RecordType::A), a_records("www.local.com", 1));
+    stale.put_negative(cache_key("missing.local.com", message::RecordType::A), cache::CachedAnswer::NXDomain, 1);
+    clock.advance(time::Duration::from_secs(1));
+
+    // Expired entries are never served as fresh
+    assert!(stale.get(&cache_key("www.local.com", message::RecordType::A)).is_none());
+    let Some(cache::CachedAnswer::Records(records)) = stale.get_stale(&cache_key("www.local.com", message::RecordType::A)) else {
+        panic!("Should be kept for the stale window.");
+    };
+    assert_eq!(records[0].ttl, cache::STALE_TTL);
+    assert_eq!(stale.get_stale(&cache_key("missing.local.com", message::RecordType::A)), Some(cache::CachedAnswer::NXDomain));
+    // Checking for a stale entry does not count as serving it
+    assert!(stale.has_stale(&cache_key("www.local.com", message::RecordType::A)));
+    assert!(!stale.has_stale(&cache_key("other.local.com", message::RecordType::A)));
+    assert_eq!(stale.stats().stale_serves, 2);
+    assert!(format!("{}", stale).contains("Stale Serves: 2"));
+
+    // Without a window, expired means gone
+    assert!(strict.get(&cache_key("www.local.com", message::RecordType::A)).is_none());
+    assert!(!strict.has_stale(&cache_key("www.local.com", message::RecordType::A)));
+    assert!(strict.get_stale(&cache_key("www.local.com", message::RecordType::A)).is_none());
+    assert_eq!(strict.stats().stale_serves, 0);
+
+    // Past the window, stale entries are gone too
+    clock.advance(time::Duration::from_secs(60));
+    assert!(stale.get_stale(&cache_key("www.local.com", message::RecordType::A)).is_none());
+}
+
+#[test]
+fn test_client_response_timer(){
+    use message::{Message, Question, RData, RecordType, CLASS_IN, MAX_UDP_PAYLOAD};
+    // Never answers, so the remote stage always times out
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let dir = std::env::temp_dir().join(format!("dns-test-timer-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let snapshot_path = dir.join("cache_snapshot.json");
+    let warm = cache::Cache::new(0, 3600, cache::CacheLimits::default());
+    warm.put(cache_key("www.example.com", RecordType::A), a_records("www.example.com", 2));
+    snapshot::save(&warm, &snapshot_path).unwrap();
+    warm.shutdown();
+    let server = test_server("timer", "[]", serde_json::json!({
+        "remote_dns_addr": silent.local_addr().unwrap().to_string(),
+        "forward_timeout_ms": 300,
+        "client_response_timeout_ms": 100,
+        "cache_snapshot": snapshot_path,
+    }));
+
+    // Wait out the restored entry, it is then only good as a stale answer
+    let saved: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&snapshot_path).unwrap()).unwrap();
+    let expires_at = std::time::UNIX_EPOCH + time::Duration::from_secs(saved["entries"][0]["expires_at"].as_u64().unwrap());
+    if let Ok(remaining) = expires_at.duration_since(std::time::SystemTime::now()) {
+        std::thread::sleep(remaining + time::Duration::from_millis(10));
+    }
+
+    // The timer runs out during the remote stage, so no later upstream is asked
+    let started = std::time::Instant::now();
+    let query = Message::query(9, Question { name: "www.example.com".to_string(), qtype: RecordType::A, qclass: CLASS_IN });
+    let response = Message::parse(&server.handle_query(&query.to_bytes(), MAX_UDP_PAYLOAD).unwrap()).unwrap();
+    assert!(started.elapsed() < time::Duration::from_secs(2));
+    assert_eq!(response.header.rcode, message::Rcode::NoError);
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(response.answers[0].data, RData::A("10.0.0.1".parse().unwrap()));
+    assert_eq!(response.answers[0].ttl, cache::STALE_TTL);
+}
+
+#[test]
+fn test_cache_prefetch(){
+
+    // With the whole TTL as the window, only popularity decides
+    let cache = cache::Cache::new(0, 3600, cache::CacheLimits::default()).with_prefetch(1.0, 2);
+    cache.put(cache_key("hot.local.com", message::RecordType::A), a_records("hot.local.com", 300));
+    cache.get(&cache_key("hot.local.com", message::RecordType::A));
+    assert!(!cache.claim_prefetch(&cache_key("hot.local.com", message::RecordType::A)));
+    cache.get(&cache_key("hot.local.com", message::RecordType::A));
+    assert!(cache.claim_prefetch(&cache_key("hot.local.com", message::RecordType::A)));
+    // Claimed once until the refreshed answer arrives
+    assert!(!cache.claim_prefetch(&cache_key("hot.local.com", message::RecordType::A)));
+    cache.put(cache_key("hot.local.com", message::RecordType::A), a_records("hot.local.com", 300));
+    assert!(cache.claim_prefetch(&cache_key("hot.local.com", message::RecordType::A)));
+    // Or until a failed refresh gives the claim up
+    cache.release_prefetch(&cache_key("hot.local.com", message::RecordType::A));
+    assert!(cache.claim_prefetch(&cache_key("hot.local.com", message::RecordType::A)));
+    cache.release_prefetch(&cache_key("cold.local.com", message::RecordType::A));
+    assert!(!cache.claim_prefetch(&cache_key("cold.local.com", message::RecordType::A)));
+    assert_eq!(cache.stats().prefetches, 3);
+
+    // Entries far from expiry are left alone
+    let clock = std::sync::Arc::new(::cache::ManualClock::new());
+    let cache = cache::Cache::with_clock(0, 3600, cache::CacheLimits::default(), clock.clone()).with_prefetch(0.1, 0);
+    cache.put(cache_key("hot.local.com", message::RecordType::A), a_records("hot.local.com", 300));
+    assert!(!cache.claim_prefetch(&cache_key("hot.local.com", message::RecordType::A)));
+    clock.advance(time::Duration::from_secs(269));
+    assert!(!cache.claim_prefetch(&cache_key("hot.local.com", message::RecordType::A)));
+    clock.advance(time::Duration::from_secs(1));
+    assert!(cache.claim_prefetch(&cache_key("hot.local.com", message::RecordType::A)));
+
+    // Disabled by default
+    let cache = cache::Cache::new(0, 3600, cache::CacheLimits::default());
+    cache.put(cache_key("hot.local.com", message::RecordType::A), a_records("hot.local.com", 300));
+    cache.get(&cache_key("hot.local.com", message::RecordType::A));
+    assert!(!cache.claim_prefetch(&cache_key("hot.local.com", message::RecordType::A)));
+}
+
+#[test]
+fn test_cache_snapshot(){
+    let path = std::env::temp_dir().join(format!("dns_cache_snapshot_{}.json", std::process::id()));
+
+    let cache = cache::Cache::new(0, 3600, cache::CacheLimits::default());
+    let www = vec![
+        record("www.local.com", message::RecordType::A, 300, message::RData::A("10.0.0.1".parse().unwrap())),
+        record("www.local.com", message::RecordType::A, 300, message::RData::A("10.0.0.2".parse().unwrap())),
+    ];
+    let txt = vec![record("www.local.com", message::RecordType::TXT, 300, message::RData::TXT(vec!["v=spf1 -all".to_string()]))];
+    let unknown = vec![record("www.local.com", message::RecordType::Unknown(99), 300, message::RData::Unknown(vec![1, 2, 3]))];
+    cache.put(cache_key("www.local.com", message::RecordType::A), www.clone());
+    cache.put(cache_key("www.local.com", message::RecordType::TXT), txt.clone());
+    cache.put(cache_key("www.local.com", message::RecordType::Unknown(99)), unknown.clone());
+    cache.put_negative(cache_key("missing.local.com", message::RecordType::A), cache::CachedAnswer::NXDomain, 300);
+    assert_eq!(snapshot::save(&cache, &path).unwrap(), 4);
+
+    let warm = cache::Cache::new(0, 3600, cache::CacheLimits::default());
+    assert_eq!(snapshot::load(&warm, &path).unwrap(), 4);
+    let strip_ttl = |answer: Option<cache::CachedAnswer>| -> Vec<message::ResourceRecord> { match answer {
+        Some(cache::CachedAnswer::Records(records)) => {
+            assert!(records.iter().all(|record| record.ttl > 290 && record.ttl <= 300));
+            records.into_iter().map(|record| message::ResourceRecord { ttl: 300, ..record }).collect()
+        }
+        other => panic!("Should be records, got {:?}", other),
+    }};
+    assert_eq!(strip_ttl(warm.get(&cache_key("www.local.com", message::RecordType::A))), www);
+    assert_eq!(strip_ttl(warm.get(&cache_key("www.local.com", message::RecordType::TXT))), txt);
+    assert_eq!(strip_ttl(warm.get(&cache_key("www.local.com", message::RecordType::Unknown(99)))), unknown);
+    assert_eq!(warm.get(&cache_key("missing.local.com", message::RecordType::A)), Some(cache::CachedAnswer::NXDomain));
+
+    // Entries that expired while the server was down are dropped
+    std::fs::write(&path, r#"{"version":1,"entries":[{"name":"old.local.com","type":1,"class":1,"expires_at":1,"answer":"nx_domain"}]}"#).unwrap();
+    let cold = cache::Cache::new(0, 3600, cache::CacheLimits::default());
+    assert_eq!(snapshot::load(&cold, &path).unwrap(), 0);
+    assert_eq!(cold.get_record_num(), 0);
+
+    // So are corrupt expiry times, instead of failing the start
+    std::fs::write(&path, format!(r#"{{"version":1,"entries":[{{"name":"bad.local.com","type":1,"class":1,"expires_at":{},"answer":"nx_domain"}}]}}"#, u64::MAX)).unwrap();
+    assert_eq!(snapshot::load(&cold, &path).unwrap(), 0);
+
+    // Layouts from the future are refused rather than misread
+    std::fs::write(&path, r#"{"version":99,"cache":{}}"#).unwrap();
+    assert!(matches!(snapshot::load(&cold, &path), Err(snapshot::SnapshotError::UnsupportedVersion(99))));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_cache_shards(){
+    let key = |index: usize| cache_key(&format!("host{}.local.com", index), message::RecordType::A);
+    let records = |index: usize| a_records(&format!("host{}.local.com", index), 300);
+
+    let cache = std::sync::Arc::new(cache::Cache::new(0, 3600, cache::CacheLimits { max_entries: 1024, ..Default::default() }));
+    assert_eq!(cache.get_shard_num(), 16);
+    // Too small to shard
+    assert_eq!(cache::Cache::new(0, 3600, cache::CacheLimits { max_entries: 100, ..Default::default() }).get_shard_num(), 1);
+
+    // Roomy enough that no thread's entry is evicted before it is read back
+    let roomy = std::sync::Arc::new(cache::Cache::new(0, 3600, cache::CacheLimits { max_entries: 16384, ..Default::default() }));
+    let handles: Vec<_> = (0..4).map(|thread| {
+        let roomy = std::sync::Arc::clone(&roomy);
+        std::thread::spawn(move || {
+            for index in (thread * 1000)..(thread * 1000 + 1000) {
+                roomy.put(key(index), records(index));
+                assert!(roomy.get(&key(index)).is_some());
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(roomy.get_record_num(), 4000);
+    assert_eq!(roomy.stats().evictions, 0);
+
+    // Every shard holds its share, so the total stays within the limit
+    for index in 0..4000 {
+        cache.put(key(index), records(index));
+    }
+    assert!(cache.get_record_num() <= 1024);
+    assert_eq!(cache.get_record_num() as u64 + cache.stats().evictions, 4000);
+}
+
+#[test]
+fn test_cache_flush(){
+    let clock = std::sync::Arc::new(::cache::ManualClock::new());
+    let cache = cache::Cache::with_clock(0, 3600, cache::CacheLimits::default(), clock.clone());
+    let fill = || {
+        cache.put(cache_key("corp.example", message::RecordType::A), a_records("corp.example", 300));
+        cache.put(cache_key("www.corp.example", message::RecordType::A), a_records("www.corp.example", 300));
+        cache.put_negative(cache_key("www.corp.example", message::RecordType::AAAA), cache::CachedAnswer::NoData, 60);
+        cache.put(cache_key("mail.corp.example", message::RecordType::A), a_records("mail.corp.example", 300));
+        cache.put(cache_key("othercorp.example", message::RecordType::A), a_records("othercorp.example", 300));
+    };
+
+    fill();
+    clock.advance(time::Duration::from_secs(10));
+    let dump = cache.dump();
+    let names: Vec<String> = dump.iter().map(|(key, _, _)| format!("{} {}", key.name, key.rtype)).collect();
+    assert_eq!(names, vec!["corp.example A", "mail.corp.example A", "othercorp.example A", "www.corp.example A", "www.corp.example AAAA"]);
+    let Some((_, cache::CachedAnswer::Records(records), remaining)) = dump.first() else {
+        panic!("Should be records.");
     };
+    assert_eq!(*remaining, time::Duration::from_secs(290));
+    assert_eq!(records[0].ttl, 290);
+    assert_eq!(dump[4].2, time::Duration::from_secs(50));
+
+    // Every type of the name, nothing else
+    assert_eq!(cache.flush_name(&"www.corp.example".parse().unwrap()), 2);
+    assert_eq!(cache.get_record_num(), 3);
+    // The suffix itself and the names below it, not names merely ending alike
+    assert_eq!(cache.flush_suffix(&"corp.example".parse().unwrap()), 2);
+    assert!(cache.get(&cache_key("othercorp.example", message::RecordType::A)).is_some());
+    assert_eq!(cache.flush_name(&"missing.example".parse().unwrap()), 0);
+
+    fill();
+    assert_eq!(cache.flush_all(), 5);
+    assert!(cache.dump().is_empty());
+}
+
+#[test]
+fn test_coalesce(){
+    use std::sync::{atomic::{AtomicUsize, Ordering}, mpsc, Arc};
+    let coalescer = Arc::new(coalesce::Coalescer::<&str, u32>::new());
+    let runs = Arc::new(AtomicUsize::new(0));
+    // Once a call has joined the flight it is sure to share its outcome
+    let wait_for_waiters = |num| {
+        while coalescer.get_waiter_num(&"www.local.com") < num {
+            std::thread::yield_now();
+        }
+    };
+
+    // The first call runs the work, the others arriving meanwhile share it
+    let (started_sender, started) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let leader = {
+        let (coalescer, runs) = (Arc::clone(&coalescer), Arc::clone(&runs));
+        std::thread::spawn(move || coalescer.run(&"www.local.com", || {
+            runs.fetch_add(1, Ordering::SeqCst);
+            started_sender.send(()).unwrap();
+            released.recv().unwrap();
+            7
+        }))
+    };
+    started.recv().unwrap();
+    let waiters: Vec<_> = (0..8).map(|_| {
+        let (coalescer, runs) = (Arc::clone(&coalescer), Arc::clone(&runs));
+        std::thread::spawn(move || coalescer.run(&"www.local.com", || {
+            runs.fetch_add(1, Ordering::SeqCst);
+            8
+        }))
+    }).collect();
+    // Another key is never held up
+    assert_eq!(coalescer.run(&"mail.local.com", || 9), 9);
+    wait_for_waiters(8);
+    release.send(()).unwrap();
+    assert_eq!(leader.join().unwrap(), 7);
+    for waiter in waiters {
+        assert_eq!(waiter.join().unwrap(), 7);
+    }
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+    assert_eq!(coalescer.get_coalesced_num(), 8);
+
+    // Nothing is kept once done
+    assert_eq!(coalescer.run(&"www.local.com", || 10), 10);
+
+    // Waiters do the work themselves when the leading call panics
+    let (started_sender, started) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let leader = {
+        let coalescer = Arc::clone(&coalescer);
+        std::thread::spawn(move || coalescer.run(&"www.local.com", || -> u32 {
+            started_sender.send(()).unwrap();
+            released.recv().unwrap();
+            panic!("upstream query failed");
+        }))
+    };
+    started.recv().unwrap();
+    let waiter = {
+        let coalescer = Arc::clone(&coalescer);
+        std::thread::spawn(move || coalescer.run(&"www.local.com", || 11))
+    };
+    wait_for_waiters(1);
+    release.send(()).unwrap();
+    assert!(leader.join().is_err());
+    assert_eq!(waiter.join().unwrap(), 11);
+    assert_eq!(coalescer.get_coalesced_num(), 8);
+}
+
+/// Compares the sharded cache with a single map behind two locks, the layout
+/// the server used before. Run with `cargo test -- --ignored --nocapture`.
+#[test]
+#[ignore]
+fn bench_cache_contention(){
+    const THREADS: usize = 8;
+    const KEYS: usize = 1000;
+    const OPERATIONS: usize = 200_000;
+
+    let logger = log::Logger::new("MyLogger", log::LogLevel::Debug);
+    let key = |index: usize| cache_key(&format!("host{}.local.com", index), message::RecordType::A);
+    let records = |index: usize| a_records(&format!("host{}.local.com", index), 300);
+    let keys: std::sync::Arc<Vec<cache::CacheKey>> = std::sync::Arc::new((0..KEYS).map(key).collect());
+
+    // Nine reads for every write, from every thread at once
+    fn run(keys: &std::sync::Arc<Vec<cache::CacheKey>>, get: impl Fn(&cache::CacheKey) + Send + Sync + 'static, put: impl Fn(&cache::CacheKey, usize) + Send + Sync + 'static) -> time::Duration {
+        let get = std::sync::Arc::new(get);
+        let put = std::sync::Arc::new(put);
+        let start = std::time::Instant::now();
+        let handles: Vec<_> = (0..THREADS).map(|thread| {
+            let (keys, get, put) = (std::sync::Arc::clone(keys), std::sync::Arc::clone(&get), std::sync::Arc::clone(&put));
+            std::thread::spawn(move || {
+                for operation in 0..OPERATIONS {
+                    let index = (operation * 31 + thread * 7) % KEYS;
+                    if operation % 10 == 0 {
+                        put(&keys[index], index);
+                    } else {
+                        get(&keys[index]);
+                    }
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        start.elapsed()
+    }
+
+    let single = std::sync::Arc::new(std::sync::Mutex::new(cache::Cache::new(0, 3600, cache::CacheLimits { shards: 1, ..Default::default() })));
+    let sharded = std::sync::Arc::new(cache::Cache::new(0, 3600, cache::CacheLimits::default()));
+    for index in 0..KEYS {
+        single.lock().unwrap().put(key(index), records(index));
+        sharded.put(key(index), records(index));
+    }
+
+    let (single_get, single_put) = (std::sync::Arc::clone(&single), std::sync::Arc::clone(&single));
+    let single_time = run(
+        &keys,
+        move |key| { single_get.lock().unwrap().get(key); },
+        move |key, index| single_put.lock().unwrap().put(key.clone(), records(index)),
+    );
+    let (sharded_get, sharded_put) = (std::sync::Arc::clone(&sharded), std::sync::Arc::clone(&sharded));
+    let sharded_time = run(
+        &keys,
+        move |key| { sharded_get.get(key); },
+        move |key, index| sharded_put.put(key.clone(), records(index)),
+    );
+
+    logger.log(log::LogLevel::Info, format!(
+        "{} threads x {} operations: Mutex<Cache> {:?}, sharded Cache {:?} ({:.1}x)",
+        THREADS, OPERATIONS, single_time, sharded_time,
+        single_time.as_secs_f64() / sharded_time.as_secs_f64()
+    ));
+}
+
+#[test]
+fn test_message(){
+    // Query for www.local.com A, as sent by `dig www.local.com`
+    let query_bytes = [
+        0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x03, b'w', b'w', b'w', 0x05, b'l', b'o', b'c', b'a', b'l', 0x03, b'c', b'o', b'm', 0x00,
+        0x00, 0x01, 0x00, 0x01,
+    ];
+    let query = message::Message::parse(&query_bytes).expect("Failed to parse query");
+    assert_eq!(query.header.id, 0x1234);
+    assert!(query.header.recursion_desired);
+    assert_eq!(query.questions[0].name, "www.local.com");
+    assert_eq!(query.questions[0].qtype, message::RecordType::A);
+    assert_eq!(query.to_bytes(), query_bytes);
+
+    let mut response = message::Message::response_to(&query);
+    response.answers.push(message::ResourceRecord {
+        name: "www.local.com".to_string(),
+        rtype: message::RecordType::A,
+        class: message::CLASS_IN,
+        ttl: 5,
+        data: message::RData::A("127.0.0.1".parse().unwrap()),
+    });
+    let response_bytes = response.to_bytes();
+    // The answer name is compressed to a pointer at the question name
+    assert_eq!(&response_bytes[query_bytes.len()..query_bytes.len() + 2], &[0xC0, 0x0C]);
+    assert_eq!(message::Message::parse(&response_bytes).unwrap(), response);
+
+    assert_eq!(message::Message::parse(&query_bytes[..20]), Err(message::MessageError::UnexpectedEnd));
+    let looping = [0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xC0, 0x0C];
+    assert_eq!(message::Message::parse(&looping), Err(message::MessageError::PointerLoop));
+}
+
+#[test]
+fn test_resolution(){
+    use dns::Resolution;
+    assert_eq!(Resolution::Answer(Vec::new()).rcode(), message::Rcode::NoError);
+    assert_eq!(Resolution::NoData.rcode(), message::Rcode::NoError);
+    assert_eq!(Resolution::NXDomain.rcode(), message::Rcode::NXDomain);
+    assert_eq!(Resolution::ServFail.rcode(), message::Rcode::ServFail);
+    assert_eq!(Resolution::Refused.rcode(), message::Rcode::Refused);
+
+    // Refused names and names that fail validation never reach an upstream
+    let server = test_server("resolution", "[]", serde_json::json!({"refused_domains": ["blocked.com"]}));
+    for (id, name) in [(1, "ads.blocked.com"), (2, "blocked.com"), (3, "exa mple.com")] {
+        let question = message::Question { name: name.to_string(), qtype: message::RecordType::A, qclass: message::CLASS_IN };
+        let query = message::Message::query(id, question);
+        let reply = server.handle_query(&query.to_bytes(), message::MAX_UDP_PAYLOAD).unwrap();
+        let response = message::Message::parse(&reply).unwrap();
+        assert_eq!(response.header.id, id);
+        assert_eq!(response.header.rcode, message::Rcode::Refused, "{}", name);
+        assert!(response.answers.is_empty());
+    }
+
+    let nx_domain = serde_json::json!({"Status": 3, "Authority": []});
+    let dns_response = utils::DnsResponse::from_json(nx_domain).unwrap();
+    assert_eq!(dns_response.status, utils::GOOGLE_DNS_NXDOMAIN);
+    assert!(dns_response.answers.is_empty());
+    assert_eq!(dns_response.negative_ttl(), None);
+
+    // Negative answers carry the zone's SOA, its MINIMUM caps the TTL
+    let nx_domain = serde_json::json!({"Status": 3, "Authority": [
+        {"name": "binciluo.com.", "type": 6, "TTL": 1800, "data": "ns1.binciluo.com. admin.binciluo.com. 2024010101 7200 3600 1209600 60"},
+    ]});
+    let dns_response = utils::DnsResponse::from_json(nx_domain).unwrap();
+    assert_eq!(dns_response.authorities.len(), 1);
+    assert_eq!(dns_response.negative_ttl(), Some(60));
+
+    // Both the CNAME and the A record it leads to are kept
+    let answer = serde_json::json!({"Status": 0, "Answer": [
+        {"name": "www.binciluo.com.", "type": 5, "TTL": 300, "data": "binciluo.com."},
+        {"name": "binciluo.com.", "type": 1, "TTL": 300, "data": "59.110.7.2"},
+    ]});
+    let dns_response = utils::DnsResponse::from_json(answer).unwrap();
+    assert_eq!(dns_response.answers.len(), 2);
+    assert_eq!(dns_response.answers[0].data, message::RData::CNAME("binciluo.com".to_string()));
+    assert_eq!(dns_response.answers[1].name, "binciluo.com");
+    assert_eq!(dns_response.answers[1].data, message::RData::A("59.110.7.2".parse().unwrap()));
+    assert!(utils::DnsResponse::from_json(serde_json::json!({})).is_err());
+}
+
+#[test]
+fn test_zone(){
+    use message::{RData, RecordType};
+    use zone::{Zone, ZoneAnswer};
+    let contents = r#"[
+        ["www.local.com", "127.0.0.1"],
+        {"domain": "www.local.com", "type": "AAAA", "value": "::1"},
+        {"domain": "alias.local.com", "type": "CNAME", "value": "www.local.com."},
+        {"domain": "local.com", "type": "MX", "value": "10 mail.local.com", "ttl": 600},
+        {"domain": "local.com", "type": "TXT", "value": "v=spf1 -all"},
+        {"domain": "_sip._udp.local.com", "type": "SRV", "value": "0 5 5060 sip.local.com"}
+    ]"#;
+    let zone = Zone::parse(contents, 5).expect("Failed to parse zone");
+    assert_eq!(zone.get_record_num(), 6);
+
+    let ZoneAnswer::Records(records) = zone.lookup(&"alias.local.com".parse().unwrap(), RecordType::AAAA) else {
+        panic!("Alias should resolve locally.");
+    };
+    assert_eq!(records[0].data, RData::CNAME("www.local.com".to_string()));
+    assert_eq!(records[1].data, RData::AAAA("::1".parse().unwrap()));
+    assert_eq!(zone.lookup(&"local.com".parse().unwrap(), RecordType::A), ZoneAnswer::NoData);
+    assert_eq!(zone.lookup(&"other.com".parse().unwrap(), RecordType::A), ZoneAnswer::NotFound);
+
+    let ZoneAnswer::Records(records) = zone.lookup(&"local.com".parse().unwrap(), RecordType::ANY) else {
+        panic!("local.com should have records.");
+    };
+    assert_eq!(records[0].ttl, 600);
+    assert_eq!(records[1].data, RData::TXT(vec!["v=spf1 -all".to_string()]));
+
+    // Every record type survives a round trip through the wire format
+    let mut response = message::Message::default();
+    for name in ["alias.local.com", "local.com", "_sip._udp.local.com"] {
+        if let ZoneAnswer::Records(records) = zone.lookup(&name.parse().unwrap(), RecordType::ANY) {
+            response.answers.extend(records);
+        }
+    }
+    response.answers.push(message::ResourceRecord {
+        name: "local.com".to_string(),
+        rtype: RecordType::SOA,
+        class: message::CLASS_IN,
+        ttl: 5,
+        data: RData::from_presentation(RecordType::SOA, "ns.local.com. admin.local.com. 1 3600 600 86400 60").unwrap(),
+    });
+    assert_eq!(message::Message::parse(&response.to_bytes()).unwrap(), response);
+
+    assert!(Zone::parse(r#"[{"domain": "a.com", "type": "A", "value": "::1"}]"#, 5).is_err());
+    assert!(Zone::parse(r#"[{"domain": "a.com", "type": "HINFO", "value": "x"}]"#, 5).is_err());
+}
+
+#[test]
+fn test_zone_order(){
+    use message::RecordType;
+    use zone::{Zone, ZoneAnswer};
+    let contents = r#"[
+        ["api.local.com", "10.0.0.1"],
+        ["api.local.com", "10.0.0.2"],
+        {"domain": "api.local.com", "value": "10.0.0.3", "order": "round_robin"},
+        {"domain": "web.local.com", "value": ["10.0.1.1", "10.0.1.2", "10.0.1.3"], "order": "shuffle"},
+        {"domain": "db.local.com", "value": ["10.0.2.1", "10.0.2.2"]}
+    ]"#;
+    let zone = Zone::parse(contents, 5).expect("Failed to parse zone");
+    let addresses = |name: &str| -> Vec<String> {
+        let ZoneAnswer::Records(records) = zone.lookup(&name.parse().unwrap(), RecordType::A) else {
+            panic!("{} should resolve locally.", name);
+        };
+        records.iter().map(|record| record.data.to_string()).collect()
+    };
+
+    // Earlier pairs are no longer overwritten by later ones
+    assert_eq!(addresses("api.local.com"), ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    assert_eq!(addresses("api.local.com"), ["10.0.0.2", "10.0.0.3", "10.0.0.1"]);
+    assert_eq!(addresses("api.local.com"), ["10.0.0.3", "10.0.0.1", "10.0.0.2"]);
+
+    let mut shuffled = addresses("web.local.com");
+    shuffled.sort();
+    assert_eq!(shuffled, ["10.0.1.1", "10.0.1.2", "10.0.1.3"]);
+
+    assert_eq!(addresses("db.local.com"), addresses("db.local.com"));
+
+    let conflicting = r#"[
+        {"domain": "a.com", "value": "10.0.0.1", "order": "shuffle"},
+        {"domain": "a.com", "value": "10.0.0.2", "order": "fixed"}
+    ]"#;
+    assert!(Zone::parse(conflicting, 5).is_err());
+
+    let empty = r#"[{"domain": "a.com", "value": [], "order": "round_robin"}]"#;
+    assert!(Zone::parse(empty, 5).is_err());
+}
+
+#[test]
+fn test_zone_wildcard(){
+    use message::{RData, RecordType};
+    use zone::{Zone, ZoneAnswer};
+    let contents = r#"[
+        ["*.dev.local.com", "10.0.0.1"],
+        ["alice.dev.local.com", "10.0.0.2"],
+        ["db.bob.dev.local.com", "10.0.0.3"],
+        {"domain": "*.dev.local.com", "type": "TXT", "value": "dev box"},
+        {"domain": "*.alias.local.com", "type": "CNAME", "value": "alice.dev.local.com"}
+    ]"#;
+    let zone = Zone::parse(contents, 5).expect("Failed to parse zone");
+    let address = |name: &str| -> Option<RData> {
+        match zone.lookup(&name.parse().unwrap(), RecordType::A) {
+            ZoneAnswer::Records(records) => records.last().map(|record| record.data.clone()),
+            _ => None,
+        }
+    };
+
+    // Synthesized records carry the query name as their owner
+    let ZoneAnswer::Records(records) = zone.lookup(&"carol.dev.local.com".parse().unwrap(), RecordType::A) else {
+        panic!("Wildcard should match.");
+    };
+    assert_eq!(records[0].name, "carol.dev.local.com");
+    assert_eq!(address("x.y.dev.local.com"), Some(RData::A("10.0.0.1".parse().unwrap())));
+
+    // Explicit records win, and an empty non-terminal blocks the wildcard
+    assert_eq!(address("alice.dev.local.com"), Some(RData::A("10.0.0.2".parse().unwrap())));
+    assert_eq!(zone.lookup(&"bob.dev.local.com".parse().unwrap(), RecordType::A), ZoneAnswer::NotFound);
+    assert_eq!(zone.lookup(&"x.bob.dev.local.com".parse().unwrap(), RecordType::A), ZoneAnswer::NotFound);
+    assert_eq!(zone.lookup(&"alice.dev.local.com".parse().unwrap(), RecordType::TXT), ZoneAnswer::NoData);
+    assert_eq!(zone.lookup(&"dev.local.com".parse().unwrap(), RecordType::A), ZoneAnswer::NotFound);
+
+    // A wildcard CNAME is followed into the explicit target
+    let ZoneAnswer::Records(records) = zone.lookup(&"anything.alias.local.com".parse().unwrap(), RecordType::A) else {
+        panic!("Wildcard alias should resolve locally.");
+    };
+    assert_eq!(records[0].name, "anything.alias.local.com");
+    assert_eq!(records[1].data, RData::A("10.0.0.2".parse().unwrap()));
+}
+
+#[test]
+fn test_zone_alias(){
+    use message::{Message, Question, RData, RecordType, CLASS_IN, MAX_UDP_PAYLOAD};
+    use zone::{Zone, ZoneAnswer};
+    let contents = r#"[
+        {"domain": "alias.local.com", "type": "CNAME", "value": "hop.local.com"},
+        {"domain": "hop.local.com", "type": "CNAME", "value": "www.example.com."}
+    ]"#;
+    let zone = Zone::parse(contents, 5).expect("Failed to parse zone");
+    let ZoneAnswer::Alias { chain, target } = zone.lookup(&"alias.local.com".parse().unwrap(), RecordType::A) else {
+        panic!("Alias should leave the local zone.");
+    };
+    assert_eq!(chain.len(), 2);
+    assert_eq!(chain[1].data, RData::CNAME("www.example.com".to_string()));
+    assert_eq!(target, "www.example.com".parse().unwrap());
+
+    // The target is answered from a warm cache, so no upstream is asked
+    let dir = std::env::temp_dir().join(format!("dns-test-alias-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let snapshot_path = dir.join("cache_snapshot.json");
+    let warm = cache::Cache::new(0, 3600, cache::CacheLimits::default());
+    warm.put(cache_key("www.example.com", RecordType::A), a_records("www.example.com", 300));
+    snapshot::save(&warm, &snapshot_path).unwrap();
+    warm.shutdown();
+    let server = test_server("alias", contents, serde_json::json!({"cache_snapshot": snapshot_path}));
+
+    let query = Message::query(7, Question { name: "alias.local.com".to_string(), qtype: RecordType::A, qclass: CLASS_IN });
+    let reply = server.handle_query(&query.to_bytes(), MAX_UDP_PAYLOAD).unwrap();
+    let response = Message::parse(&reply).unwrap();
+    assert_eq!(response.header.rcode, message::Rcode::NoError);
+    let answers: Vec<(String, RData)> = response.answers.into_iter().map(|record| (record.name, record.data)).collect();
+    assert_eq!(answers, [
+        ("alias.local.com".to_string(), RData::CNAME("hop.local.com".to_string())),
+        ("hop.local.com".to_string(), RData::CNAME("www.example.com".to_string())),
+        ("www.example.com".to_string(), RData::A("10.0.0.1".parse().unwrap())),
+    ]);
+}
+
+#[test]
+fn test_forwarder(){
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, UdpSocket};
+    use message::{Message, RData, RecordType, ResourceRecord};
+
+    // Stand-in upstream answering on UDP and TCP of the same port
+    let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    let tcp_upstream = TcpListener::bind(upstream_addr).unwrap();
+    let answer = |query: &Message, data: RData| {
+        let mut response = Message::response_to(query);
+        response.answers.push(ResourceRecord {
+            name: query.questions[0].name.clone(),
+            rtype: query.questions[0].qtype,
+            class: message::CLASS_IN,
+            ttl: 300,
+            data,
+        });
+        response
+    };
+    let server = std::thread::spawn(move || {
+        let mut buffer = [0; 512];
+        let (len, client) = upstream.recv_from(&mut buffer).unwrap();
+        let query = Message::parse(&buffer[..len]).unwrap();
+        // A reply with the wrong ID and one for another name are both ignored
+        let mut spoofed = answer(&query, RData::A("6.6.6.6".parse().unwrap()));
+        spoofed.header.id = query.header.id.wrapping_add(1);
+        upstream.send_to(&spoofed.to_bytes(), client).unwrap();
+        let mut other = spoofed.clone();
+        other.header.id = query.header.id;
+        other.questions[0].name = "evil.example.com".to_string();
+        upstream.send_to(&other.to_bytes(), client).unwrap();
+        let reply = answer(&query, RData::A("93.184.216.34".parse().unwrap()));
+        upstream.send_to(&reply.to_bytes(), client).unwrap();
+
+        // Truncated over UDP, complete over TCP
+        let (len, client) = upstream.recv_from(&mut buffer).unwrap();
+        let query = Message::parse(&buffer[..len]).unwrap();
+        let mut truncated = Message::response_to(&query);
+        truncated.header.truncated = true;
+        upstream.send_to(&truncated.to_bytes(), client).unwrap();
+        let (mut stream, _) = tcp_upstream.accept().unwrap();
+        let mut length = [0; 2];
+        stream.read_exact(&mut length).unwrap();
+        let mut framed = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut framed).unwrap();
+        let query = Message::parse(&framed).unwrap();
+        let reply = answer(&query, RData::TXT(vec!["x".repeat(255), "y".repeat(255)])).to_bytes();
+        stream.write_all(&(reply.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(&reply).unwrap();
+    });
+
+    let forwarder = forwarder::Forwarder::new(upstream_addr, time::Duration::from_secs(2));
+    let response = forwarder.query(&"www.example.com".parse().unwrap(), RecordType::A).expect("UDP query failed");
+    assert_eq!(response.answers[0].data, RData::A("93.184.216.34".parse().unwrap()));
+    let response = forwarder.query(&"big.example.com".parse().unwrap(), RecordType::TXT).expect("TCP fallback failed");
+    assert!(!response.header.truncated);
+    assert_eq!(response.answers[0].data, RData::TXT(vec!["x".repeat(255), "y".repeat(255)]));
+    server.join().unwrap();
+
+    assert_eq!(forwarder::Forwarder::parse_addr("8.8.8.8"), Some("8.8.8.8:53".parse().unwrap()));
+    assert_eq!(forwarder::Forwarder::parse_addr("[::1]:5353"), Some("[::1]:5353".parse().unwrap()));
+    assert_eq!(forwarder::Forwarder::parse_addr("dns.google"), None);
+
+    // Nobody answers on this socket, so the query times out
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let forwarder = forwarder::Forwarder::new(silent.local_addr().unwrap(), time::Duration::from_millis(100));
+    assert!(matches!(forwarder.query(&"www.example.com".parse().unwrap(), RecordType::A), Err(forwarder::ForwardError::Timeout)));
+}
+
+#[test]
+fn test_system_resolver(){
+    use forwarder::SystemResolver;
+    let contents = "# Generated by NetworkManager\n\
+        search corp.example\n\
+        nameserver 127.0.0.53\n\
+        nameserver fe80::1%eth0\n\
+        nameserver not-an-address\n\
+        options edns0 trust-ad\n";
+    let resolver = SystemResolver::from_resolv_conf(contents, time::Duration::from_secs(1))
+        .expect("Nameservers should be found");
+    assert_eq!(
+        resolver.get_nameservers(),
+        ["127.0.0.53:53".parse().unwrap(), "[fe80::1]:53".parse().unwrap()]
+    );
+
+    let resolver = resolver.without(|addr| addr.ip().is_loopback()).unwrap();
+    assert_eq!(resolver.get_nameservers(), ["[fe80::1]:53".parse().unwrap()]);
+    assert!(resolver.without(|_| true).is_none());
+    assert!(SystemResolver::from_resolv_conf("search corp.example\n", time::Duration::from_secs(1)).is_none());
+}
+
+#[test]
+fn test_domain(){
+    use domain::{DomainError, DomainName};
+    let name: DomainName = "WWW.Local.COM.".parse().unwrap();
+    assert_eq!(name.as_str(), "www.local.com");
+    assert_eq!(name, "www.local.com".parse().unwrap());
+    assert_eq!(name.labels_reversed().collect::<Vec<_>>(), ["com", "local", "www"]);
+    assert!(name.is_subdomain_of(&"local.com".parse().unwrap()));
+    assert!(!name.is_subdomain_of(&"cal.com".parse().unwrap()));
+    assert!(name.is_subdomain_of(&DomainName::root()));
+    assert_eq!(DomainName::parse(".").unwrap().to_string(), ".");
+    assert!(DomainName::parse("_sip._udp.local.com").is_ok());
+    assert!(DomainName::parse("*.dev.local.com").is_ok());
+
+    assert_eq!(DomainName::parse(""), Err(DomainError::Empty));
+    assert_eq!(DomainName::parse("www..local.com"), Err(DomainError::EmptyLabel));
+    assert_eq!(DomainName::parse(".local.com"), Err(DomainError::EmptyLabel));
+    assert_eq!(DomainName::parse("a.*.local.com"), Err(DomainError::MisplacedWildcard));
+    assert_eq!(DomainName::parse("www.local.com;reboot"), Err(DomainError::InvalidCharacter(';')));
+    assert_eq!(DomainName::parse("$(id).com"), Err(DomainError::InvalidCharacter('$')));
+    // Names from the wire are rejected, never rewritten into another name
+    assert_eq!(DomainName::parse("exa\0mple.com"), Err(DomainError::InvalidCharacter('\0')));
+    assert_eq!(DomainName::parse("exa mple.com"), Err(DomainError::InvalidCharacter(' ')));
+    assert!(matches!(DomainName::parse(&"a".repeat(64)), Err(DomainError::LabelTooLong(_))));
+    let long_name = vec!["a".repeat(63); 4].join(".");
+    assert_eq!(DomainName::parse(&long_name), Err(DomainError::NameTooLong));
+    assert!(DomainName::parse(&long_name[2..]).is_ok());
+}
+
+#[test]
+fn test_domain_idna(){
+    use domain::DomainName;
+    let name: DomainName = "百度.中国".parse().unwrap();
+    assert_eq!(name.as_str(), "xn--wxtr44c.xn--fiqs8s");
+    assert_eq!(name, "XN--WXTR44C.xn--fiqs8s.".parse().unwrap());
+    assert_eq!(name.to_unicode(), "百度.中国");
+    assert_eq!("www.local.com".parse::<DomainName>().unwrap().to_unicode(), "www.local.com");
+    assert_eq!(DomainName::parse("*.开发.local.com").unwrap().as_str(), "*.xn--oor13x.local.com");
+
+    // Unicode entries in the local zone answer queries sent in A-label form
+    let contents = r#"[["主页.local.com", "10.0.0.1"]]"#;
+    let zone = zone::Zone::parse(contents, 5).expect("Failed to parse zone");
+    let queried = message::Message::parse(&message::Message::query(1, message::Question {
+        name: "xn--tiqp32p.local.com".to_string(),
+        qtype: message::RecordType::A,
+        qclass: message::CLASS_IN,
+    }).to_bytes()).unwrap();
+    let name: DomainName = queried.questions[0].name.parse().unwrap();
+    assert!(matches!(zone.lookup(&name, message::RecordType::A), zone::ZoneAnswer::Records(_)));
+
+    // Names inside record values are converted and validated the same way
+    let contents = r#"[
+        {"domain": "alias.local.com", "type": "CNAME", "value": "主页.local.com."},
+        {"domain": "local.com", "type": "MX", "value": ["10 邮件.local.com", "20 MAIL.local.com"]},
+        {"domain": "null.local.com", "type": "MX", "value": "0 ."},
+        ["主页.local.com", "10.0.0.1"]
+    ]"#;
+    let zone = zone::Zone::parse(contents, 5).expect("Failed to parse zone");
+    let zone::ZoneAnswer::Records(records) = zone.lookup(&"alias.local.com".parse().unwrap(), message::RecordType::A) else {
+        panic!("Unicode alias target should resolve locally.");
+    };
+    assert_eq!(records[0].data, message::RData::CNAME("xn--tiqp32p.local.com".to_string()));
+    let zone::ZoneAnswer::Records(records) = zone.lookup(&"local.com".parse().unwrap(), message::RecordType::MX) else {
+        panic!("MX records should be found.");
+    };
+    let exchanges: Vec<String> = records.iter().map(|record| record.data.to_string()).collect();
+    assert_eq!(exchanges, ["10 xn--5nq051n.local.com.", "20 mail.local.com."]);
+    for invalid in [
+        r#"[{"domain": "a.com", "type": "CNAME", "value": "bad!.com"}]"#,
+        r#"[{"domain": "a.com", "type": "SRV", "value": "0 5 5060 sip..a.com"}]"#,
+        r#"[{"domain": "a.com", "type": "NS", "value": "ns.a*.com"}]"#,
+    ] {
+        assert!(matches!(zone::Zone::parse(invalid, 5), Err(zone::ZoneError::BadValue { .. })), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_request_queue(){
+    use message::{Message, Question, Rcode, RecordType, CLASS_IN};
+    let server = test_server("queue", "[]", serde_json::json!({}));
+    let client_address: std::net::SocketAddr = "127.0.0.1:5353".parse().unwrap();
+    let query = |id| Message::query(id, Question { name: "www.local.com".to_string(), qtype: RecordType::A, qclass: CLASS_IN });
+    let (sender, receiver) = crossbeam::channel::bounded(2);
+
+    // Queued while there is room, with nothing to send yet
+    for id in 1..=2 {
+        assert_eq!(server.enqueue_request(&sender, query(id).to_bytes(), client_address), None);
+    }
+    assert_eq!(receiver.len(), 2);
+
+    // Shed once full, with a SERVFAIL for the same query
+    let reply = server.enqueue_request(&sender, query(0x1234).to_bytes(), client_address).unwrap();
+    let response = Message::parse(&reply).unwrap();
+    assert_eq!(response.header.id, 0x1234);
+    assert!(response.header.response);
+    assert_eq!(response.header.rcode, Rcode::ServFail);
+    assert_eq!(response.questions, query(0x1234).questions);
+    assert!(response.answers.is_empty());
+    assert_eq!(receiver.len(), 2);
+
+    // Responses and garbage are dropped without a reply
+    let bounced = Message::response_to(&query(3));
+    assert_eq!(server.enqueue_request(&sender, bounced.to_bytes(), client_address), None);
+    assert_eq!(server.enqueue_request(&sender, vec![0x12], client_address), None);
+
+    // Queued requests are still delivered in order
+    let (request, address) = receiver.recv().unwrap();
+    assert_eq!(Message::parse(&request).unwrap().header.id, 1);
+    assert_eq!(address, client_address);
+}
+
+#[test]
+fn test_exit(){
+    use std::sync::Arc;
+    let server = Arc::new(test_server("exit", "[]", serde_json::json!({})));
+
+    // Without exit the wait lasts its whole timeout
+    let start = std::time::Instant::now();
+    assert!(!server.wait_for_exit(time::Duration::from_millis(50)));
+    assert!(start.elapsed() >= time::Duration::from_millis(50));
+
+    let waiter = {
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || {
+            let start = std::time::Instant::now();
+            (server.wait_for_exit(time::Duration::from_secs(10)), start.elapsed())
+        })
+    };
+    // Give the waiter time to block, an earlier exit would be seen right away too
+    std::thread::sleep(time::Duration::from_millis(50));
+    server.exit();
+    let (exited, waited) = waiter.join().unwrap();
+    assert!(exited);
+    assert!(waited < time::Duration::from_secs(1));
+    assert!(server.is_exited());
+    // Once exiting, waits return at once
+    assert!(server.wait_for_exit(time::Duration::from_secs(10)));
+}
+
+#[test]
+fn test_tcp(){
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use message::{Message, Question, RecordType, CLASS_IN, MAX_UDP_PAYLOAD};
+    let addresses: Vec<String> = (1..=40).map(|host| format!("10.0.0.{}", host)).collect();
+    let zone = serde_json::json!([{"domain": "big.local.com", "value": addresses}]).to_string();
+    let server = Arc::new(test_server("tcp", &zone, serde_json::json!({})));
+    let query = Message::query(0x4242, Question { name: "big.local.com".to_string(), qtype: RecordType::A, qclass: CLASS_IN });
+
+    // Forty A records do not fit in a datagram
+    let udp = Message::parse(&server.handle_query(&query.to_bytes(), MAX_UDP_PAYLOAD).unwrap()).unwrap();
+    assert!(udp.header.truncated);
+    assert!(udp.answers.is_empty());
+
+    dns::DNSServer::run_processing_tcp(&server);
+    let port = server.get_local_port();
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+    // Two queries on one connection, each with its length prefix
+    for _ in 0..2 {
+        let bytes = query.to_bytes();
+        stream.write_all(&(bytes.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(&bytes).unwrap();
+        let mut length = [0; 2];
+        stream.read_exact(&mut length).unwrap();
+        let mut reply = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut reply).unwrap();
+        assert!(reply.len() > MAX_UDP_PAYLOAD);
+        let response = Message::parse(&reply).unwrap();
+        assert_eq!(response.header.id, 0x4242);
+        assert!(!response.header.truncated);
+        assert_eq!(response.answers.len(), 40);
+    }
+
+    server.exit();
+    dns::DNSServer::wait_exit(&server);
 }
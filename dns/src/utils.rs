@@ -1,21 +1,60 @@
 use serde_json::Value;
 use std::fs::File;
 use std::io::Read;
-use std::process::Command;
-use reqwest;
+
+use crate::domain::DomainName;
+use crate::message::{RData, RecordType, ResourceRecord, CLASS_IN};
+
+pub const GOOGLE_DNS_NOERROR: u64 = 0;
+pub const GOOGLE_DNS_NXDOMAIN: u64 = 3;
 
 #[derive(Debug)]
 pub(crate) struct DnsResponse {
     // 定义你需要提取的DNS响应字段
-    // 这里假设只关注A记录的IP地址
-    pub ip_address: String,
+    // Status 与 DNS RCODE 相同, 无法解析的记录会被跳过
+    pub status: u64,
+    pub answers: Vec<ResourceRecord>,
+    // 否定回答时 Authority 中带有 SOA
+    pub authorities: Vec<ResourceRecord>,
 }
 
 impl DnsResponse {
     // 解析DNS响应
     pub fn from_json(json: serde_json::Value) -> Result<DnsResponse, Box<dyn std::error::Error>> {
-        let ip_address = json["Answer"][0]["data"].as_str().unwrap_or("Unknown").to_string();
-        Ok(DnsResponse { ip_address })
+        let status = json["Status"]
+            .as_u64()
+            .ok_or("Missing Status in response")?;
+        let answers = Self::parse_records(&json["Answer"]);
+        let authorities = Self::parse_records(&json["Authority"]);
+        Ok(DnsResponse { status, answers, authorities })
+    }
+
+    /// How long a NXDOMAIN or NODATA answer may be cached, the same way as
+    /// `Message::negative_ttl`.
+    pub fn negative_ttl(&self) -> Option<u32> {
+        self.authorities.iter().find_map(|record| match record.data {
+            RData::SOA { minimum, .. } => Some(record.ttl.min(minimum)),
+            _ => None,
+        })
+    }
+
+    fn parse_records(records: &Value) -> Vec<ResourceRecord> {
+        records
+            .as_array()
+            .map(|records| records.iter().filter_map(Self::parse_record).collect())
+            .unwrap_or_default()
+    }
+
+    fn parse_record(record: &Value) -> Option<ResourceRecord> {
+        let rtype = RecordType::from(u16::try_from(record["type"].as_u64()?).ok()?);
+        let data = RData::from_presentation(rtype, record["data"].as_str()?).ok()?;
+        Some(ResourceRecord {
+            name: record["name"].as_str()?.trim_end_matches('.').to_string(),
+            rtype,
+            class: CLASS_IN,
+            ttl: u32::try_from(record["TTL"].as_u64()?).ok()?,
+            data,
+        })
     }
 }
 
@@ -27,51 +66,30 @@ pub fn _read_json_file(file_path: &str) -> Result<Value, io::Error> {
     Ok(json_data)
 }
 
-use std::io::{self, ErrorKind};
+use std::io;
 use std::sync::mpsc::{self, Receiver};
 use std::thread;
 
-pub fn exec_shell_command(cmd: &str) -> Result<String, io::Error> {
-    let output = Command::new("bash").arg("-c").arg(cmd).output()?;
-
-    if output.status.success() {
-        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
-    } else {
-        Err(io::Error::new(
-            ErrorKind::Other,
-            format!(
-                "Command failed with status code {}: {}",
-                output.status,
-                String::from_utf8_lossy(&output.stderr)
-            ),
-        ))
-    }
-}
-
-pub fn query_google_dns(domain: &str, record_type: &str) -> Result<DnsResponse, Box<dyn std::error::Error>> {
+pub fn query_google_dns(client: &reqwest::blocking::Client, domain: &DomainName, record_type: &str) -> Result<DnsResponse, Box<dyn std::error::Error>> {
     let url = format!("https://dns.google/resolve?name={}&type={}&do=1", domain, record_type);
-    let response_text = reqwest::blocking::get(&url)?.text()?;
+    let response_text = client.get(&url).send()?.text()?;
     let json: serde_json::Value = serde_json::from_str(&response_text)?;
     let dns_response = DnsResponse::from_json(json)?;
     Ok(dns_response)
 }
 
-pub fn clean_io(origin: &str) -> String {
-    // 移除空白字符和非打印字符
-    let cleaned_domain = origin
-        .trim()
-        .chars()
-        .filter(|c| c.is_ascii_graphic())
-        .collect::<String>();
-    cleaned_domain
-}
-
 pub fn spawn_stdin_channel() -> Receiver<String> {
     let (tx, rx) = mpsc::channel::<String>();
+    // Ends at end of input or once nobody listens, dropping the sender
     thread::spawn(move || loop {
         let mut buffer = String::new();
-        io::stdin().read_line(&mut buffer).unwrap();
-        tx.send(buffer).unwrap();
+        match io::stdin().read_line(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if tx.send(buffer).is_err() {
+            break;
+        }
     });
     rx
 }
@@ -0,0 +1,425 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+
+use crate::{
+    domain::{DomainError, DomainName},
+    message::{RData, RecordType, ResourceRecord, CLASS_IN},
+};
+
+const MAX_CNAME_CHAIN: usize = 8;
+const WILDCARD_LABEL: &str = "*";
+
+#[derive(Debug, Deserialize)]
+struct DNSRecord {
+    domain: DomainName,
+    #[serde(rename = "type", default = "default_record_type")]
+    record_type: String,
+    #[serde(alias = "ip")]
+    value: RecordValue,
+    ttl: Option<u32>,
+    order: Option<AnswerOrder>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RecordValue {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// How the records of one set are ordered in each answer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnswerOrder {
+    /// Always in configuration order.
+    #[default]
+    Fixed,
+    /// Rotated by one position on every answer.
+    RoundRobin,
+    /// Randomly permuted on every answer.
+    Shuffle,
+}
+
+/// All records of one type at one name.
+#[derive(Debug)]
+struct RecordSet {
+    rtype: RecordType,
+    order: Option<AnswerOrder>,
+    records: Vec<ResourceRecord>,
+    next: AtomicUsize,
+}
+
+impl RecordSet {
+    fn answer(&self) -> Vec<ResourceRecord> {
+        let mut records = self.records.clone();
+        match self.order.unwrap_or_default() {
+            AnswerOrder::Fixed => {}
+            AnswerOrder::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % records.len();
+                records.rotate_left(start);
+            }
+            AnswerOrder::Shuffle => records.shuffle(&mut rand::thread_rng()),
+        }
+        records
+    }
+}
+
+fn default_record_type() -> String {
+    "A".to_string()
+}
+
+/// Entries are either the original `[domain, ip]` pairs, which are A records,
+/// or objects such as `{"domain": ..., "type": "MX", "value": "10 mail.example"}`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ZoneEntry {
+    Pair(DomainName, String),
+    Record(DNSRecord),
+}
+
+#[derive(Debug)]
+pub enum ZoneError {
+    Json(serde_json::Error),
+    UnsupportedType(String),
+    BadValue { domain: DomainName, value: String },
+    CnameConflict(DomainName),
+    OrderConflict(DomainName),
+}
+
+impl fmt::Display for ZoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZoneError::Json(e) => write!(f, "Invalid zone JSON: {}", e),
+            ZoneError::UnsupportedType(rtype) => write!(f, "Unsupported record type {}", rtype),
+            ZoneError::BadValue { domain, value } => {
+                write!(f, "Invalid record value {:?} for {}", value, domain)
+            }
+            ZoneError::CnameConflict(domain) => {
+                write!(f, "{} has a CNAME alongside other records", domain)
+            }
+            ZoneError::OrderConflict(domain) => {
+                write!(
+                    f,
+                    "{} has conflicting answer orders for one record set",
+                    domain
+                )
+            }
+        }
+    }
+}
+
+impl Error for ZoneError {}
+
+impl From<serde_json::Error> for ZoneError {
+    fn from(e: serde_json::Error) -> Self {
+        ZoneError::Json(e)
+    }
+}
+
+/// Result of looking a name up in the local zone.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ZoneAnswer {
+    /// Matching records, possibly preceded by the CNAME chain that led to them.
+    Records(Vec<ResourceRecord>),
+    /// The name exists locally but has no records of the requested type.
+    NoData,
+    /// The CNAME chain leads out of the local zone, to `target`, which has
+    /// to be resolved elsewhere and its records appended to `chain`.
+    Alias {
+        chain: Vec<ResourceRecord>,
+        target: DomainName,
+    },
+    /// The name is not part of the local zone.
+    NotFound,
+}
+
+/// One label of the zone tree. Names are stored with their labels reversed,
+/// so `www.local.com` lives at `com -> local -> www`.
+#[derive(Debug, Default)]
+struct ZoneNode {
+    children: HashMap<String, ZoneNode>,
+    sets: Vec<RecordSet>,
+}
+
+impl ZoneNode {
+    fn record_num(&self) -> usize {
+        let own: usize = self.sets.iter().map(|set| set.records.len()).sum();
+        own + self
+            .children
+            .values()
+            .map(ZoneNode::record_num)
+            .sum::<usize>()
+    }
+}
+
+/// Where a name was found in the zone tree.
+enum Match<'a> {
+    /// A node with records at exactly this name.
+    Exact(&'a [RecordSet]),
+    /// No node at this name; records synthesized from the closest encloser's
+    /// `*` child (RFC 4592).
+    Wildcard(&'a [RecordSet]),
+    /// A node exists only because names below it have records.
+    EmptyNonTerminal,
+    NotFound,
+}
+
+#[derive(Debug, Default)]
+pub struct Zone {
+    root: ZoneNode,
+}
+
+impl Zone {
+    pub fn parse(contents: &str, default_ttl: u32) -> Result<Zone, ZoneError> {
+        let entries: Vec<ZoneEntry> = serde_json::from_str(contents)?;
+        let mut zone = Zone::default();
+        for entry in entries {
+            let record = match entry {
+                ZoneEntry::Pair(domain, ip) => DNSRecord {
+                    domain,
+                    record_type: default_record_type(),
+                    value: RecordValue::One(ip),
+                    ttl: None,
+                    order: None,
+                },
+                ZoneEntry::Record(record) => record,
+            };
+            zone.insert(record, default_ttl)?;
+        }
+        Ok(zone)
+    }
+
+    fn insert(&mut self, record: DNSRecord, default_ttl: u32) -> Result<(), ZoneError> {
+        let rtype = parse_record_type(&record.record_type)
+            .ok_or_else(|| ZoneError::UnsupportedType(record.record_type.clone()))?;
+        let name = record.domain;
+        let values = match record.value {
+            RecordValue::One(value) => vec![value],
+            RecordValue::Many(values) => values,
+        };
+        // A set without records would have nothing to answer with
+        if values.is_empty() {
+            return Err(ZoneError::BadValue {
+                domain: name,
+                value: String::new(),
+            });
+        }
+        let mut node = &mut self.root;
+        for label in name.labels_reversed() {
+            node = node.children.entry(label.to_string()).or_default();
+        }
+        let sets = &mut node.sets;
+        let set = match sets.iter().position(|set| set.rtype == rtype) {
+            Some(index) => &mut sets[index],
+            None => {
+                sets.push(RecordSet {
+                    rtype,
+                    order: None,
+                    records: Vec::new(),
+                    next: AtomicUsize::new(0),
+                });
+                sets.last_mut().unwrap()
+            }
+        };
+
+        if let Some(order) = record.order {
+            if set.order.is_some_and(|existing| existing != order) {
+                return Err(ZoneError::OrderConflict(name));
+            }
+            set.order = Some(order);
+        }
+
+        for value in values {
+            let data = RData::from_presentation(rtype, &value)
+                .ok()
+                .and_then(|data| normalize_names(data).ok())
+                .ok_or_else(|| ZoneError::BadValue {
+                    domain: name.clone(),
+                    value: value.clone(),
+                })?;
+            if set.records.iter().any(|existing| existing.data == data) {
+                continue;
+            }
+            set.records.push(ResourceRecord {
+                name: name.as_str().to_string(),
+                rtype,
+                class: CLASS_IN,
+                ttl: record.ttl.unwrap_or(default_ttl),
+                data,
+            });
+        }
+
+        let has_cname = sets.iter().any(|set| set.rtype == RecordType::CNAME);
+        let record_num: usize = sets.iter().map(|set| set.records.len()).sum();
+        if has_cname && record_num > 1 {
+            return Err(ZoneError::CnameConflict(name));
+        }
+        Ok(())
+    }
+
+    fn find(&self, name: &DomainName) -> Match<'_> {
+        let mut node = &self.root;
+        for label in name.labels_reversed() {
+            match node.children.get(label) {
+                Some(child) => node = child,
+                // `node` is the closest encloser, only its own wildcard may apply
+                None => {
+                    return match node.children.get(WILDCARD_LABEL) {
+                        Some(wildcard) if !wildcard.sets.is_empty() => {
+                            Match::Wildcard(&wildcard.sets)
+                        }
+                        _ => Match::NotFound,
+                    }
+                }
+            }
+        }
+        if node.sets.is_empty() {
+            Match::EmptyNonTerminal
+        } else {
+            Match::Exact(&node.sets)
+        }
+    }
+
+    /// Explicit names always win over wildcards. An empty non-terminal blocks
+    /// wildcard synthesis but, as we are not authoritative for its parent, is
+    /// still reported as `NotFound` so the query is forwarded.
+    pub fn lookup(&self, name: &DomainName, qtype: RecordType) -> ZoneAnswer {
+        let mut owner = name.clone();
+        let mut answers = Vec::new();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let sets = match self.find(&owner) {
+                Match::Exact(sets) | Match::Wildcard(sets) => sets,
+                Match::EmptyNonTerminal | Match::NotFound if answers.is_empty() => {
+                    return ZoneAnswer::NotFound;
+                }
+                // The alias points outside the local zone
+                Match::EmptyNonTerminal | Match::NotFound => {
+                    return ZoneAnswer::Alias {
+                        chain: answers,
+                        target: owner,
+                    };
+                }
+            };
+
+            let matching: Vec<ResourceRecord> = sets
+                .iter()
+                .filter(|set| qtype == RecordType::ANY || set.rtype == qtype)
+                .flat_map(RecordSet::answer)
+                .map(|record| ResourceRecord {
+                    name: owner.as_str().to_string(),
+                    ..record
+                })
+                .collect();
+            if !matching.is_empty() {
+                answers.extend(matching);
+                break;
+            }
+
+            // Follow the alias while it stays inside the local zone
+            let Some(
+                alias @ ResourceRecord {
+                    data: RData::CNAME(target),
+                    ..
+                },
+            ) = sets.first().and_then(|set| set.records.first())
+            else {
+                break;
+            };
+            answers.push(ResourceRecord {
+                name: owner.as_str().to_string(),
+                ..alias.clone()
+            });
+            match DomainName::parse(target) {
+                Ok(target) => owner = target,
+                Err(_) => break,
+            }
+        }
+
+        if answers.is_empty() {
+            ZoneAnswer::NoData
+        } else {
+            ZoneAnswer::Records(answers)
+        }
+    }
+
+    pub fn get_record_num(&self) -> usize {
+        self.root.record_num()
+    }
+}
+
+/// Runs the names inside `data` through `DomainName::parse`, so that targets
+/// are validated and stored in A-label form like owners are. The root, which
+/// `from_presentation` leaves empty, is kept as is.
+fn normalize_names(data: RData) -> Result<RData, DomainError> {
+    let name = |name: String| -> Result<String, DomainError> {
+        if name.is_empty() {
+            return Ok(name);
+        }
+        DomainName::parse(&name).map(|name| name.as_str().to_string())
+    };
+    let data = match data {
+        RData::CNAME(target) => RData::CNAME(name(target)?),
+        RData::NS(target) => RData::NS(name(target)?),
+        RData::PTR(target) => RData::PTR(name(target)?),
+        RData::MX {
+            preference,
+            exchange,
+        } => RData::MX {
+            preference,
+            exchange: name(exchange)?,
+        },
+        RData::SRV {
+            priority,
+            weight,
+            port,
+            target,
+        } => RData::SRV {
+            priority,
+            weight,
+            port,
+            target: name(target)?,
+        },
+        RData::SOA {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        } => RData::SOA {
+            mname: name(mname)?,
+            rname: name(rname)?,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        },
+        data => data,
+    };
+    Ok(data)
+}
+
+pub fn parse_record_type(name: &str) -> Option<RecordType> {
+    let rtype = match name.to_ascii_uppercase().as_str() {
+        "A" => RecordType::A,
+        "AAAA" => RecordType::AAAA,
+        "CNAME" => RecordType::CNAME,
+        "MX" => RecordType::MX,
+        "TXT" => RecordType::TXT,
+        "SRV" => RecordType::SRV,
+        "NS" => RecordType::NS,
+        "PTR" => RecordType::PTR,
+        "SOA" => RecordType::SOA,
+        _ => return None,
+    };
+    Some(rtype)
+}
@@ -48,7 +48,7 @@ impl Logger {
 
         let level_str = format!("{}", log_level);
         let _lock = self.mutex.lock().unwrap();
-        println!("{}{}{}", level_str, message, "\x1B[0m");
+        println!("{}{}\x1B[0m", level_str, message);
     }
 
     pub fn get_log_level(&self) -> LogLevel {